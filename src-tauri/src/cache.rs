@@ -0,0 +1,157 @@
+use std::{future::Future, path::Path, path::PathBuf};
+
+use chrono::{DateTime, Local};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tauri::{AppHandle, Emitter};
+
+use crate::{error::Error, persist};
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntry<T> {
+    fetched_at: DateTime<Local>,
+    #[serde(default)]
+    dirty: bool,
+    data: T,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Cached<T> {
+    #[serde(flatten)]
+    pub data: T,
+    pub cache_key: String,
+    pub stale: bool,
+    pub fetched_at: DateTime<Local>,
+}
+
+pub fn cache_key(url_path: &str, query: &[(&str, &str)]) -> String {
+    if query.is_empty() {
+        return url_path.to_string();
+    }
+    let query = query
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join("&");
+    format!("{}?{}", url_path, query)
+}
+
+// Raw responses kept next to their ETag / Last-Modified validators, so a
+// `304 Not Modified` can be answered from disk.
+#[derive(Serialize, Deserialize)]
+pub struct ValidatedResponse {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub body: serde_json::Value,
+}
+
+fn validated_path(data_dir: &Path, key: &str) -> PathBuf {
+    data_dir
+        .join("cache")
+        .join("http")
+        .join(format!("{}.json", sha256::digest(key)))
+}
+
+pub fn read_validated(data_dir: &Path, key: &str) -> Option<ValidatedResponse> {
+    persist::read_json(&validated_path(data_dir, key)).ok()
+}
+
+pub fn write_validated(
+    data_dir: &Path,
+    key: &str,
+    validated: &ValidatedResponse,
+) -> Result<(), Error> {
+    persist::write_json(&validated_path(data_dir, key), validated)
+}
+
+fn cache_path(data_dir: &Path, key: &str) -> PathBuf {
+    data_dir
+        .join("cache")
+        .join(format!("{}.json", sha256::digest(key)))
+}
+
+fn read_entry<T: DeserializeOwned>(data_dir: &Path, key: &str) -> Option<CacheEntry<T>> {
+    persist::read_json(&cache_path(data_dir, key)).ok()
+}
+
+fn write_entry<T: Serialize>(
+    data_dir: &Path,
+    key: &str,
+    entry: &CacheEntry<T>,
+) -> Result<(), Error> {
+    persist::write_json(&cache_path(data_dir, key), entry)
+}
+
+fn store<T: Serialize>(data_dir: &Path, key: &str, data: T) -> Result<Cached<T>, Error> {
+    let entry = CacheEntry {
+        fetched_at: Local::now(),
+        dirty: false,
+        data,
+    };
+    write_entry(data_dir, key, &entry)?;
+    Ok(Cached {
+        data: entry.data,
+        cache_key: key.to_string(),
+        stale: false,
+        fetched_at: entry.fetched_at,
+    })
+}
+
+// Dirty entries are still served while offline but are never treated as
+// immutable, so the next read refreshes them from the server.
+pub fn mark_dirty(data_dir: &Path, key: &str) -> Result<(), Error> {
+    let Some(mut entry) = read_entry::<serde_json::Value>(data_dir, key) else {
+        return Ok(());
+    };
+    entry.dirty = true;
+    write_entry(data_dir, key, &entry)
+}
+
+// Serves the cached copy immediately when there is one and refreshes it in
+// the background, emitting `event` with the fresh data once it arrives.
+// Immutable entries are served as is without contacting the server.
+pub async fn read_through<T, F, Fut>(
+    app_handle: &AppHandle,
+    data_dir: &Path,
+    key: &str,
+    event: &'static str,
+    immutable: bool,
+    fetch: F,
+) -> Result<Cached<T>, Error>
+where
+    T: Serialize + DeserializeOwned + Clone + Send + 'static,
+    F: FnOnce(AppHandle, PathBuf) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<T, Error>> + Send + 'static,
+{
+    let Some(entry) = read_entry::<T>(data_dir, key) else {
+        let data = fetch(app_handle.clone(), data_dir.to_path_buf()).await?;
+        return store(data_dir, key, data);
+    };
+
+    if immutable && !entry.dirty {
+        return Ok(Cached {
+            data: entry.data,
+            cache_key: key.to_string(),
+            stale: false,
+            fetched_at: entry.fetched_at,
+        });
+    }
+
+    let app_handle = app_handle.clone();
+    let data_dir = data_dir.to_path_buf();
+    let refresh_key = key.to_string();
+    tauri::async_runtime::spawn(async move {
+        let Ok(data) = fetch(app_handle.clone(), data_dir.clone()).await else {
+            return;
+        };
+        if let Ok(cached) = store(&data_dir, &refresh_key, data) {
+            let _ = app_handle.emit(event, cached);
+        }
+    });
+
+    Ok(Cached {
+        data: entry.data,
+        cache_key: key.to_string(),
+        stale: true,
+        fetched_at: entry.fetched_at,
+    })
+}
@@ -0,0 +1,96 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{error::Error, kdf};
+
+const VERIFIER_LEN: usize = 32;
+// Bounds the memory a server can make the client spend, 256 MiB.
+const MAX_M_COST: u32 = 256 * 1024;
+// Below the OWASP Argon2id baseline of 19 MiB and two passes a proof can be
+// brute forced offline, so whoever answers the challenge could learn the
+// password from it.
+const MIN_M_COST: u32 = 19 * 1024;
+const MIN_T_COST: u32 = 2;
+
+// Issued by `/auth/challenge`. The nonce is single use, so a captured proof
+// cannot be replayed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Challenge {
+    pub nonce: String,
+    pub salt: String,
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+    // False while the account only has the legacy password key.
+    pub registered: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChallengeResponse {
+    pub nonce: String,
+    pub proof: String,
+}
+
+// Moves an account off the legacy password key. The key is sent one last time
+// to authorize the new verifier, and the server refuses it from then on.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Registration {
+    pub key: String,
+    pub nonce: String,
+    pub verifier: String,
+}
+
+// The verifier is Argon2id over the password itself, so it cannot be derived
+// from a captured legacy key.
+pub async fn verifier(password: &str, challenge: &Challenge) -> Result<Vec<u8>, Error> {
+    if challenge.m_cost > MAX_M_COST {
+        return Err(Error::Incompatible(format!(
+            "server asked for {} KiB of key derivation memory",
+            challenge.m_cost
+        )));
+    }
+    if challenge.m_cost < MIN_M_COST || challenge.t_cost < MIN_T_COST {
+        return Err(Error::Incompatible(format!(
+            "server asked for too weak a key derivation ({} KiB, {} passes)",
+            challenge.m_cost, challenge.t_cost
+        )));
+    }
+    let salt = STANDARD
+        .decode(&challenge.salt)
+        .map_err(|e| Error::Decode(e.to_string()))?;
+    let verifier: [u8; VERIFIER_LEN] = kdf::argon2id(
+        password.as_bytes().to_vec(),
+        salt,
+        challenge.m_cost,
+        challenge.t_cost,
+        challenge.p_cost,
+    )
+    .await?;
+    Ok(verifier.to_vec())
+}
+
+pub fn proof(verifier: &[u8], nonce: &str) -> Result<String, Error> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(verifier).map_err(|e| Error::Client(e.to_string()))?;
+    mac.update(nonce.as_bytes());
+    Ok(STANDARD.encode(mac.finalize().into_bytes()))
+}
+
+pub async fn respond(challenge: Challenge, password: &str) -> Result<ChallengeResponse, Error> {
+    let verifier = verifier(password, &challenge).await?;
+    Ok(ChallengeResponse {
+        proof: proof(&verifier, &challenge.nonce)?,
+        nonce: challenge.nonce,
+    })
+}
+
+pub async fn register(challenge: Challenge, password: &str) -> Result<Registration, Error> {
+    let verifier = verifier(password, &challenge).await?;
+    Ok(Registration {
+        key: sha256::digest(password),
+        nonce: challenge.nonce,
+        verifier: STANDARD.encode(verifier),
+    })
+}
@@ -17,7 +17,7 @@ pub struct BlockType {
     pub color: Color,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct TimeBlock {
     pub start_time: DateTime<Local>,
     pub end_time: DateTime<Local>,
@@ -25,27 +25,27 @@ pub struct TimeBlock {
     pub title: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct CurrentBlock {
     pub block_type_id: u8,
     pub current_block_name: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Trend {
     pub day: NaiveDate,
     pub time_spent: Duration,
     pub block_type_id: u8,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Analysis {
     pub percentages: Vec<f32>,
     pub trends: Vec<Trend>,
     pub blocktypes: Vec<BlockType>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct HomeData {
     pub blocktypes: Vec<BlockType>,
     pub daydata: Vec<TimeBlock>,
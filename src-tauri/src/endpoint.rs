@@ -0,0 +1,85 @@
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServerEndpoint {
+    pub scheme: String,
+    pub host: String,
+    pub port: u16,
+    pub base_path: String,
+}
+
+impl ServerEndpoint {
+    pub fn parse(address: &str) -> Result<Self, Error> {
+        let address = address.trim();
+        if address.is_empty() {
+            return Err(Error::InvalidAddress("Server address is empty".to_string()));
+        }
+        let address = if address.contains("://") {
+            address.to_string()
+        } else {
+            format!("http://{}", address)
+        };
+        let url = Url::parse(&address).map_err(|e| Error::InvalidAddress(e.to_string()))?;
+
+        let scheme = match url.scheme() {
+            "http" | "https" => url.scheme().to_string(),
+            scheme => {
+                return Err(Error::InvalidAddress(format!(
+                    "Unsupported server scheme: {}",
+                    scheme
+                )))
+            }
+        };
+        if !url.username().is_empty() || url.password().is_some() {
+            return Err(Error::InvalidAddress(
+                "Server address must not contain credentials".to_string(),
+            ));
+        }
+        if url.query().is_some() || url.fragment().is_some() {
+            return Err(Error::InvalidAddress(
+                "Server address must not contain a query or fragment".to_string(),
+            ));
+        }
+        let host = url
+            .host_str()
+            .ok_or(Error::InvalidAddress(
+                "Server address has no host".to_string(),
+            ))?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+        let port = url.port_or_known_default().ok_or(Error::InvalidAddress(
+            "Server address has no port".to_string(),
+        ))?;
+        let base_path = url.path().trim_end_matches('/').to_string();
+
+        Ok(Self {
+            scheme,
+            host,
+            port,
+            base_path,
+        })
+    }
+
+    pub fn url(&self, url_path: &str) -> String {
+        format!(
+            "{}://{}:{}{}/{}",
+            self.scheme,
+            self.host_for_url(),
+            self.port,
+            self.base_path,
+            url_path.trim_start_matches('/')
+        )
+    }
+
+    fn host_for_url(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        }
+    }
+}
@@ -1,8 +1,171 @@
-use serde::{Deserialize, Serialize};
+use std::fmt;
 
-#[derive(Debug, Serialize, Deserialize)]
+use serde::{Serialize, Serializer};
+
+#[derive(Debug, Clone)]
 pub enum Error {
     Client(String),
-    Server(String),
+    Io(String),
+    Decode(String),
+    InvalidInput(String),
+    InvalidAddress(String),
+    Tls(String),
+    Network(String),
+    Timeout(String),
+    Interrupted(String),
+    Http { status: u16, body: Option<String> },
+    Unauthorized,
     LoginExpired,
+    Locked,
+    Cancelled,
+    Incompatible(String),
+    Unsupported(String),
+    InsecureLogin(String),
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    Client,
+    Io,
+    Decode,
+    InvalidInput,
+    InvalidAddress,
+    Tls,
+    Network,
+    Timeout,
+    Interrupted,
+    Http,
+    Unauthorized,
+    LoginExpired,
+    Locked,
+    Cancelled,
+    Incompatible,
+    Unsupported,
+    InsecureLogin,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RecoveryAction {
+    Relogin,
+    Retry,
+    CheckServerAddress,
+    Unlock,
+    ConfirmInsecureLogin,
+}
+
+#[derive(Serialize)]
+struct ErrorPayload<'a> {
+    kind: ErrorKind,
+    message: String,
+    status: Option<u16>,
+    body: Option<&'a str>,
+    retryable: bool,
+    action: Option<RecoveryAction>,
+}
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Client(_) => ErrorKind::Client,
+            Error::Io(_) => ErrorKind::Io,
+            Error::Decode(_) => ErrorKind::Decode,
+            Error::InvalidInput(_) => ErrorKind::InvalidInput,
+            Error::InvalidAddress(_) => ErrorKind::InvalidAddress,
+            Error::Tls(_) => ErrorKind::Tls,
+            Error::Network(_) => ErrorKind::Network,
+            Error::Timeout(_) => ErrorKind::Timeout,
+            Error::Interrupted(_) => ErrorKind::Interrupted,
+            Error::Http { .. } => ErrorKind::Http,
+            Error::Unauthorized => ErrorKind::Unauthorized,
+            Error::LoginExpired => ErrorKind::LoginExpired,
+            Error::Locked => ErrorKind::Locked,
+            Error::Cancelled => ErrorKind::Cancelled,
+            Error::Incompatible(_) => ErrorKind::Incompatible,
+            Error::Unsupported(_) => ErrorKind::Unsupported,
+            Error::InsecureLogin(_) => ErrorKind::InsecureLogin,
+        }
+    }
+
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            Error::Http { status, .. } => Some(*status),
+            Error::Unauthorized => Some(401),
+            Error::LoginExpired => Some(511),
+            _ => None,
+        }
+    }
+
+    pub fn retryable(&self) -> bool {
+        match self {
+            Error::Network(_) | Error::Timeout(_) | Error::Interrupted(_) => true,
+            Error::Http { status, .. } => *status == 429 || *status >= 500,
+            _ => false,
+        }
+    }
+
+    pub fn action(&self) -> Option<RecoveryAction> {
+        match self {
+            Error::Unauthorized | Error::LoginExpired => Some(RecoveryAction::Relogin),
+            Error::InvalidAddress(_) | Error::Tls(_) => Some(RecoveryAction::CheckServerAddress),
+            Error::Locked => Some(RecoveryAction::Unlock),
+            Error::InsecureLogin(_) => Some(RecoveryAction::ConfirmInsecureLogin),
+            _ if self.retryable() => Some(RecoveryAction::Retry),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Client(message) => write!(f, "{}", message),
+            Error::Io(message) => write!(f, "File error: {}", message),
+            Error::Decode(message) => write!(f, "Unexpected data: {}", message),
+            Error::InvalidInput(message) => write!(f, "Invalid input: {}", message),
+            Error::InvalidAddress(message) => write!(f, "Invalid server address: {}", message),
+            Error::Tls(message) => write!(f, "TLS error: {}", message),
+            Error::Network(message) => write!(f, "Could not reach the server: {}", message),
+            Error::Timeout(message) => write!(f, "The server took too long: {}", message),
+            Error::Interrupted(message) => {
+                write!(
+                    f,
+                    "The connection dropped before the server answered: {}",
+                    message
+                )
+            }
+            Error::Http { status, .. } => write!(f, "Server responded with status {}", status),
+            Error::Unauthorized => write!(f, "Unauthorized"),
+            Error::LoginExpired => write!(f, "Login expired, please log in again"),
+            Error::Locked => write!(f, "Stored credentials are locked by a passphrase"),
+            Error::Cancelled => write!(f, "Request cancelled"),
+            Error::Incompatible(message) => write!(f, "Incompatible server: {}", message),
+            Error::Unsupported(message) => write!(f, "Not supported: {}", message),
+            Error::InsecureLogin(message) => write!(f, "Insecure login: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Serialize for Error {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let body = match self {
+            Error::Http { body, .. } => body.as_deref(),
+            _ => None,
+        };
+        ErrorPayload {
+            kind: self.kind(),
+            message: self.to_string(),
+            status: self.status(),
+            body,
+            retryable: self.retryable(),
+            action: self.action(),
+        }
+        .serialize(serializer)
+    }
 }
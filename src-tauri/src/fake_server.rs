@@ -0,0 +1,580 @@
+// In-process stand-in for the scheduler server, used by the tests to drive the
+// real request paths over HTTP. Responses can be scripted per path to inject
+// failures, and the access token can be expired to exercise the 511 handling.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+use crate::{
+    challenge::{self, Challenge, ChallengeResponse, Registration},
+    error::Error,
+    netutils::ApiClient,
+    tauface::meta::login_internal,
+};
+
+pub const USERNAME: &str = "tester";
+pub const PASSWORD: &str = "hunter2";
+
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    // Set when the request arrived in absolute form, i.e. through a proxy.
+    pub proxied: bool,
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+#[derive(Debug, Clone)]
+pub enum Scripted {
+    Respond { status: u16, body: Value },
+    // Closes the connection without answering, after the request arrived.
+    Disconnect,
+}
+
+struct FakeState {
+    generation: usize,
+    access_token: String,
+    refresh_token: String,
+    expires_in: i64,
+    refresh_count: usize,
+    revoked_count: usize,
+    // What challenge proofs are checked against, none while the account only
+    // has the legacy password key.
+    verifier: Option<Vec<u8>>,
+    // Challenges handed out and not yet answered.
+    challenges: Vec<Challenge>,
+    server_info: Option<Value>,
+    home_data: Value,
+    // Every change the server has seen, in order; the cursor is the count.
+    sync_changes: Vec<Value>,
+    requests: Vec<RecordedRequest>,
+    scripted: HashMap<String, VecDeque<Scripted>>,
+}
+
+pub struct FakeServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<FakeState>>,
+    // Not running while the server is offline.
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl FakeServer {
+    pub async fn start() -> FakeServer {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap_or_else(|e| panic!("failed to bind fake server: {}", e));
+        let addr = listener
+            .local_addr()
+            .unwrap_or_else(|e| panic!("fake server has no address: {}", e));
+        let verifier = challenge::verifier(PASSWORD, &fake_challenge("", true))
+            .await
+            .unwrap_or_else(|e| panic!("failed to derive the fake verifier: {}", e));
+        let state = Arc::new(Mutex::new(FakeState {
+            generation: 1,
+            access_token: "access-1".to_string(),
+            refresh_token: "refresh-1".to_string(),
+            expires_in: 3600,
+            refresh_count: 0,
+            revoked_count: 0,
+            verifier: Some(verifier),
+            challenges: Vec::new(),
+            server_info: Some(json!({
+                "version": "1.0.0",
+                "api_version": 1,
+                "features": ["split", "adjust", "analysis", "sync", "challenge_login"],
+            })),
+            home_data: sample_home_data(),
+            sync_changes: Vec::new(),
+            requests: Vec::new(),
+            scripted: HashMap::new(),
+        }));
+        let task = Mutex::new(Some(accept(listener, state.clone())));
+        FakeServer { addr, state, task }
+    }
+
+    // Closes the listening socket, so connections are refused like they are
+    // by an unreachable server.
+    pub async fn go_offline(&self) {
+        let task = self
+            .task
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .take();
+        if let Some(task) = task {
+            task.abort();
+            let _ = task.await;
+        }
+    }
+
+    pub async fn go_online(&self) {
+        let listener = TcpListener::bind(self.addr)
+            .await
+            .unwrap_or_else(|e| panic!("failed to rebind fake server: {}", e));
+        *self
+            .task
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) =
+            Some(accept(listener, self.state.clone()));
+    }
+
+    pub fn address(&self) -> String {
+        self.addr.to_string()
+    }
+
+    fn state(&self) -> MutexGuard<'_, FakeState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn script(&self, path: &str, response: Scripted) {
+        self.state()
+            .scripted
+            .entry(path.to_string())
+            .or_default()
+            .push_back(response);
+    }
+
+    // Rotates the tokens on the server side, so the client's access token is
+    // rejected with 511 until it refreshes.
+    pub fn expire_access_token(&self) {
+        let mut state = self.state();
+        state.generation += 1;
+        state.access_token = format!("access-{}", state.generation);
+    }
+
+    pub fn set_expires_in(&self, expires_in: i64) {
+        self.state().expires_in = expires_in;
+    }
+
+    pub fn set_server_info(&self, server_info: Option<Value>) {
+        self.state().server_info = server_info;
+    }
+
+    // As for an account created before challenge login, which still has to
+    // register a verifier.
+    pub fn forget_verifier(&self) {
+        self.state().verifier = None;
+    }
+
+    pub fn has_verifier(&self) -> bool {
+        self.state().verifier.is_some()
+    }
+
+    pub fn set_home_data(&self, home_data: Value) {
+        self.state().home_data = home_data;
+    }
+
+    // One of `{"block": ..}`, `{"blocktype": ..}` or `{"deleted": start_time}`.
+    pub fn push_sync_change(&self, change: Value) {
+        self.state().sync_changes.push(change);
+    }
+
+    pub fn refresh_count(&self) -> usize {
+        self.state().refresh_count
+    }
+
+    pub fn revoked_count(&self) -> usize {
+        self.state().revoked_count
+    }
+
+    pub fn access_token(&self) -> String {
+        self.state().access_token.clone()
+    }
+
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state().requests.clone()
+    }
+
+    pub fn requests_to(&self, path: &str) -> Vec<RecordedRequest> {
+        self.requests()
+            .into_iter()
+            .filter(|request| request.path == path)
+            .collect()
+    }
+}
+
+impl Drop for FakeServer {
+    fn drop(&mut self) {
+        if let Ok(Some(task)) = self.task.get_mut().map(Option::take) {
+            task.abort();
+        }
+    }
+}
+
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> TempDir {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "time-scheduler-client-test-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path)
+            .unwrap_or_else(|e| panic!("failed to create temp dir: {}", e));
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+pub struct Fixture {
+    pub server: FakeServer,
+    pub data_dir: TempDir,
+    pub client: ApiClient,
+}
+
+impl Fixture {
+    pub async fn logged_in() -> Fixture {
+        let fixture = Fixture::logged_out().await;
+        fixture
+            .login()
+            .await
+            .unwrap_or_else(|e| panic!("login against fake server failed: {}", e));
+        fixture
+    }
+
+    pub async fn logged_out() -> Fixture {
+        Fixture {
+            server: FakeServer::start().await,
+            data_dir: TempDir::new(),
+            client: ApiClient::default(),
+        }
+    }
+
+    pub async fn login(&self) -> Result<(), Error> {
+        login_internal(
+            self.data_dir.path(),
+            USERNAME,
+            PASSWORD,
+            &self.server.address(),
+            None,
+            None,
+            false,
+        )
+        .await?;
+        self.client.reset().await;
+        Ok(())
+    }
+
+    pub fn data_dir(&self) -> &Path {
+        self.data_dir.path()
+    }
+}
+
+pub fn sample_home_data() -> Value {
+    json!({
+        "blocktypes": [
+            { "id": 1, "name": "Work", "color": { "r": 255, "g": 0, "b": 0 } },
+            { "id": 2, "name": "Rest", "color": { "r": 0, "g": 255, "b": 0 } },
+        ],
+        "daydata": [
+            {
+                "start_time": "2024-01-01T08:00:00+00:00",
+                "end_time": "2024-01-01T09:00:00+00:00",
+                "block_type_id": 1,
+                "title": "First",
+            },
+            {
+                "start_time": "2024-01-01T09:00:00+00:00",
+                "end_time": "2024-01-01T10:00:00+00:00",
+                "block_type_id": 2,
+                "title": "Second",
+            },
+        ],
+        "currentblock": { "block_type_id": 1, "current_block_name": "Writing tests" },
+    })
+}
+
+fn accept(listener: TcpListener, state: Arc<Mutex<FakeState>>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(handle_connection(stream, state.clone()));
+        }
+    })
+}
+
+async fn handle_connection(mut stream: TcpStream, state: Arc<Mutex<FakeState>>) {
+    let Some(request) = read_request(&mut stream).await else {
+        return;
+    };
+    let response = {
+        let mut state = state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        state.requests.push(request.clone());
+        match state
+            .scripted
+            .get_mut(&request.path)
+            .and_then(|queue| queue.pop_front())
+        {
+            Some(scripted) => scripted,
+            None => route(&mut state, &request),
+        }
+    };
+    let Scripted::Respond { status, body } = response else {
+        return;
+    };
+    let mut body = body.to_string();
+    let mut status = status;
+    let mut extra_headers = String::new();
+    // Successful reads carry an ETag derived from the body, and a matching
+    // If-None-Match is answered with an empty 304.
+    if request.method == "GET" && status == 200 {
+        let etag = format!("\"{}\"", sha256::digest(&body));
+        if request.headers.get("if-none-match") == Some(&etag) {
+            status = 304;
+            body.clear();
+        }
+        extra_headers = format!("ETag: {}\r\n", etag);
+    }
+    let head = format!(
+        "HTTP/1.1 {} Fake\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n",
+        status,
+        body.len(),
+        extra_headers
+    );
+    let _ = stream.write_all(head.as_bytes()).await;
+    let _ = stream.write_all(body.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+async fn read_request(stream: &mut TcpStream) -> Option<RecordedRequest> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let head_end = loop {
+        if let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end;
+        }
+        let read = stream.read(&mut chunk).await.ok()?;
+        if read == 0 {
+            return None;
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..head_end]).to_string();
+    let mut lines = head.lines();
+    let mut request_line = lines.next()?.split_whitespace();
+    let method = request_line.next()?.to_string();
+    let target = request_line.next()?;
+    let proxied = target.starts_with("http://");
+    let target = match target.strip_prefix("http://") {
+        Some(absolute) => absolute.find('/').map_or("/", |start| &absolute[start..]),
+        None => target,
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let headers: HashMap<String, String> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+        .collect();
+
+    let content_length = headers
+        .get("content-length")
+        .and_then(|length| length.parse::<usize>().ok())
+        .unwrap_or(0);
+    let mut body = buffer[head_end + 4..].to_vec();
+    while body.len() < content_length {
+        let read = stream.read(&mut chunk).await.ok()?;
+        if read == 0 {
+            break;
+        }
+        body.extend_from_slice(&chunk[..read]);
+    }
+
+    Some(RecordedRequest {
+        method,
+        path: path.to_string(),
+        query: query.to_string(),
+        proxied,
+        headers,
+        body: String::from_utf8_lossy(&body).to_string(),
+    })
+}
+
+fn respond(status: u16, body: Value) -> Scripted {
+    Scripted::Respond { status, body }
+}
+
+fn issue_tokens(state: &mut FakeState) -> Scripted {
+    respond(
+        200,
+        json!({
+            "access_token": state.access_token,
+            "refresh_token": state.refresh_token,
+            "expires_in": state.expires_in,
+        }),
+    )
+}
+
+fn take_challenge(state: &mut FakeState, nonce: &str) -> Option<Challenge> {
+    let idx = state
+        .challenges
+        .iter()
+        .position(|challenge| challenge.nonce == nonce)?;
+    Some(state.challenges.remove(idx))
+}
+
+fn answers_challenge(state: &mut FakeState, response: &ChallengeResponse) -> bool {
+    let Some(challenge) = take_challenge(state, &response.nonce) else {
+        return false;
+    };
+    let Some(verifier) = &state.verifier else {
+        return false;
+    };
+    challenge::proof(verifier, &challenge.nonce).is_ok_and(|proof| proof == response.proof)
+}
+
+fn registers_verifier(state: &mut FakeState, registration: &Registration) -> bool {
+    if state.verifier.is_some()
+        || registration.key != sha256::digest(PASSWORD)
+        || take_challenge(state, &registration.nonce).is_none()
+    {
+        return false;
+    }
+    match STANDARD.decode(&registration.verifier) {
+        Ok(verifier) => {
+            state.verifier = Some(verifier);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+fn fake_challenge(nonce: &str, registered: bool) -> Challenge {
+    Challenge {
+        nonce: nonce.to_string(),
+        salt: "ZmFrZS1zZXJ2ZXItc2FsdA==".to_string(),
+        // The weakest parameters the client accepts keep the tests fast.
+        m_cost: 19 * 1024,
+        t_cost: 2,
+        p_cost: 1,
+        registered,
+    }
+}
+
+fn route(state: &mut FakeState, request: &RecordedRequest) -> Scripted {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/version") => match &state.server_info {
+            Some(server_info) => respond(200, server_info.clone()),
+            None => respond(404, Value::Null),
+        },
+        ("POST", "/auth/challenge") => {
+            let challenge = fake_challenge(
+                &format!("nonce-{}", state.requests.len()),
+                state.verifier.is_some(),
+            );
+            state.challenges.push(challenge.clone());
+            respond(200, json!(challenge))
+        }
+        ("POST", "/auth/login") => {
+            let body: Value = serde_json::from_str(&request.body).unwrap_or_default();
+            let accepted = if let Ok(response) =
+                serde_json::from_value::<ChallengeResponse>(body.clone())
+            {
+                answers_challenge(state, &response)
+            } else if let Ok(registration) = serde_json::from_value::<Registration>(body.clone()) {
+                registers_verifier(state, &registration)
+            } else {
+                // The legacy key only works until a verifier is registered.
+                state.verifier.is_none() && body["key"] == sha256::digest(PASSWORD)
+            };
+            if accepted {
+                issue_tokens(state)
+            } else {
+                respond(401, Value::Null)
+            }
+        }
+        ("POST", "/auth/refresh") => {
+            if request.body != state.refresh_token {
+                return respond(401, Value::Null);
+            }
+            state.refresh_count += 1;
+            state.generation += 1;
+            state.access_token = format!("access-{}", state.generation);
+            state.refresh_token = format!("refresh-{}", state.generation);
+            issue_tokens(state)
+        }
+        ("POST", "/auth/logout") => {
+            if request.body != state.refresh_token {
+                return respond(401, Value::Null);
+            }
+            state.revoked_count += 1;
+            state.generation += 1;
+            state.access_token = format!("access-{}", state.generation);
+            state.refresh_token = format!("refresh-{}", state.generation);
+            respond(200, Value::Null)
+        }
+        _ if request.headers.get("authorization")
+            != Some(&format!("Bearer {}", state.access_token)) =>
+        {
+            respond(511, Value::Null)
+        }
+        ("GET", "/state") => respond(200, state.home_data.clone()),
+        ("GET", "/timeblock/get") => respond(200, state.home_data["daydata"].clone()),
+        ("GET", "/blocktype/get") => respond(200, state.home_data["blocktypes"].clone()),
+        ("GET", "/sync") => {
+            let since = request
+                .query
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("since="))
+                .and_then(|since| since.parse::<usize>().ok())
+                .unwrap_or(0);
+            let changes = state.sync_changes.get(since..).unwrap_or_default();
+            respond(
+                200,
+                json!({
+                    "cursor": state.sync_changes.len().to_string(),
+                    "changes": changes,
+                }),
+            )
+        }
+        ("GET", "/analysis") => respond(
+            200,
+            json!({
+                "percentages": [75.0, 25.0],
+                "trends": [],
+                "blocktypes": state.home_data["blocktypes"],
+            }),
+        ),
+        ("POST", "/currentblock/change") => match serde_json::from_str::<Value>(&request.body) {
+            Ok(current) => {
+                state.home_data["currentblock"] = current;
+                respond(200, Value::Null)
+            }
+            Err(_) => respond(400, Value::Null),
+        },
+        ("POST", path) if path.starts_with("/timeblock/") || path.starts_with("/blocktype/") => {
+            respond(200, Value::Null)
+        }
+        _ => respond(404, Value::Null),
+    }
+}
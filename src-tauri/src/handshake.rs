@@ -0,0 +1,100 @@
+use std::path::Path;
+
+use reqwest::{Client, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    endpoint::ServerEndpoint,
+    error::Error,
+    netutils::{ensure_success, map_send_error},
+    tauface::meta::get_meta_internal,
+};
+
+pub const API_VERSION: u32 = 1;
+
+pub const FEATURE_SPLIT: &str = "split";
+pub const FEATURE_ADJUST: &str = "adjust";
+pub const FEATURE_ANALYSIS: &str = "analysis";
+pub const FEATURE_EVENTS: &str = "events";
+pub const FEATURE_SYNC: &str = "sync";
+pub const FEATURE_CHALLENGE_LOGIN: &str = "challenge_login";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerInfo {
+    pub version: String,
+    pub api_version: u32,
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+impl ServerInfo {
+    // Servers that predate `/version` expose exactly the endpoints this client
+    // was originally written against.
+    fn legacy() -> Self {
+        Self {
+            version: "unknown".to_string(),
+            api_version: API_VERSION,
+            features: [FEATURE_SPLIT, FEATURE_ADJUST, FEATURE_ANALYSIS]
+                .iter()
+                .map(|feature| feature.to_string())
+                .collect(),
+        }
+    }
+
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|supported| supported == feature)
+    }
+
+    pub fn ensure_compatible(&self) -> Result<(), Error> {
+        if self.api_version == API_VERSION {
+            return Ok(());
+        }
+        let update = if self.api_version > API_VERSION {
+            "client"
+        } else {
+            "server"
+        };
+        Err(Error::Incompatible(format!(
+            "server {} speaks API version {} but this client expects version {}, please update the {}",
+            self.version, self.api_version, API_VERSION, update
+        )))
+    }
+}
+
+pub async fn fetch_server_info(
+    client: &Client,
+    server: &ServerEndpoint,
+) -> Result<ServerInfo, Error> {
+    let response = client
+        .get(server.url("/version"))
+        .send()
+        .await
+        .map_err(map_send_error)?;
+    server_info_from_response(response).await
+}
+
+pub async fn server_info_from_response(response: Response) -> Result<ServerInfo, Error> {
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(ServerInfo::legacy());
+    }
+    let server_info = ensure_success(response)
+        .await?
+        .json::<ServerInfo>()
+        .await
+        .map_err(|e| Error::Decode(e.to_string()))?;
+    server_info.ensure_compatible()?;
+    Ok(server_info)
+}
+
+// Meta written before the handshake existed has no server info; those
+// installs keep working until the next login records it.
+pub async fn require_feature(data_dir: &Path, feature: &str) -> Result<(), Error> {
+    let meta = get_meta_internal(data_dir).await?;
+    match meta.server_info {
+        Some(server_info) if !server_info.supports(feature) => Err(Error::Unsupported(format!(
+            "server {} does not support {}",
+            server_info.version, feature
+        ))),
+        _ => Ok(()),
+    }
+}
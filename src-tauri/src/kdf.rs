@@ -0,0 +1,25 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+
+use crate::error::Error;
+
+// Argon2id over `secret`, for both the credentials passphrase and the login
+// verifier. It is deliberately slow, so it runs off the async workers.
+pub async fn argon2id<const N: usize>(
+    secret: Vec<u8>,
+    salt: Vec<u8>,
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+) -> Result<[u8; N], Error> {
+    tokio::task::spawn_blocking(move || {
+        let params = Params::new(m_cost, t_cost, p_cost, Some(N))
+            .map_err(|e| Error::Decode(e.to_string()))?;
+        let mut output = [0u8; N];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(&secret, &salt, &mut output)
+            .map_err(|e| Error::Decode(e.to_string()))?;
+        Ok(output)
+    })
+    .await
+    .map_err(|e| Error::Client(e.to_string()))?
+}
@@ -1,27 +1,75 @@
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
 #![deny(clippy::unwrap_used, clippy::expect_used)]
 
+use tauri::Manager;
+
+mod cache;
+mod challenge;
 mod datatypes;
+mod endpoint;
 mod error;
+#[cfg(test)]
+mod fake_server;
+mod handshake;
+mod kdf;
+mod logging;
 mod netutils;
+mod persist;
+mod poller;
+mod proxy;
+mod push;
+mod schema;
+mod secrets;
 mod tauface;
+mod tls;
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub async fn run() -> Result<(), Box<dyn std::error::Error>> {
     tauri::Builder::default()
         .plugin(tauri_plugin_shell::init())
+        .manage(netutils::ApiClient::default())
+        .manage(poller::Poller::default())
+        .setup(|app| {
+            logging::init(&app.path().app_local_data_dir()?)?;
+            push::spawn(app.handle().clone());
+            poller::spawn(app.handle().clone());
+            Ok(())
+        })
+        .on_window_event(|window, event| {
+            if let tauri::WindowEvent::Focused(true) = event {
+                window.state::<poller::Poller>().wake();
+            }
+        })
         .invoke_handler(tauri::generate_handler![
             tauface::meta::save_meta,
             tauface::meta::get_meta,
+            tauface::meta::save_server_cert,
+            tauface::meta::logout,
+            tauface::meta::unlock_credentials,
+            tauface::meta::set_credentials_passphrase,
+            tauface::meta::server_handshake,
             tauface::remote_iface::get_home_data,
             tauface::remote_iface::get_day_history,
             tauface::remote_iface::get_analysis,
+            tauface::remote_iface::cancel_request,
             tauface::remote_iface::post_next_block,
             tauface::remote_iface::post_split_block,
             tauface::remote_iface::post_adjust_block,
             tauface::remote_iface::post_change_current,
             tauface::remote_iface::post_new_block_type,
+            tauface::connection::test_connection,
+            tauface::sync::sync_history,
+            tauface::discovery::discover_servers,
+            tauface::logs::get_recent_logs,
+            tauface::outbox::get_pending_writes,
+            tauface::outbox::discard_pending_write,
+            tauface::settings::get_network_settings,
+            tauface::settings::save_network_settings,
             tauface::sun::get_sun_hours,
+            tauface::profiles::list_profiles,
+            tauface::profiles::add_profile,
+            tauface::profiles::remove_profile,
+            tauface::profiles::switch_profile,
             tauface::pallete::get_palette,
             tauface::pallete::save_palette
         ])
@@ -1,178 +1,903 @@
-use std::path::Path;
+use std::{
+    collections::HashMap, error::Error as _, future::Future, path::Path, sync::Arc, time::Duration,
+};
 
-use reqwest::{header::AUTHORIZATION, Client, StatusCode};
+use chrono::{DateTime, Local, TimeDelta};
+use reqwest::{
+    header::{
+        HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+        LAST_MODIFIED,
+    },
+    Client, ClientBuilder, Method, Response, StatusCode,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use tokio::sync::{futures::Notified, Mutex, MutexGuard, Notify, RwLock};
 
 use crate::{
+    cache::{cache_key, read_validated, write_validated, ValidatedResponse},
     error::Error,
-    tauface::meta::{get_meta_internal, LoginResponse, Meta},
+    proxy::apply_proxy,
+    tauface::{
+        meta::{get_meta_internal, save_meta_internal, LoginResponse, Meta},
+        outbox::{read_outbox, write_outbox, PendingWrite},
+        settings::{get_network_settings_internal, open_network_settings},
+    },
+    tls::{apply_tls, TlsSettings},
 };
 
-pub async fn make_get_request<T>(
-    url_path: &str,
-    data_dir: &Path,
-    query: Option<&[(&str, &str)]>,
-) -> Result<T, Error>
-where
-    T: serde::de::DeserializeOwned,
-{
-    let meta = get_meta_internal(data_dir).await?;
+const CLIENT_TIMESTAMP_HEADER: &str = "x-client-timestamp";
+const TOKEN_REFRESH_MARGIN: TimeDelta = TimeDelta::seconds(60);
 
-    let client = Client::new();
-    let response = client
-        .get(format!("http://{}{}", meta.server_ip, url_path))
-        .header(AUTHORIZATION, format!("Bearer {}", meta.access_token));
+#[derive(Default)]
+pub struct ApiClient {
+    client: RwLock<Option<Client>>,
+    stream_client: RwLock<Option<Client>>,
+    outbox: Mutex<()>,
+    in_flight: Mutex<HashMap<String, Arc<Notify>>>,
+    refresh: Mutex<()>,
+    reset_signal: Notify,
+}
 
-    let response = match query {
-        Some(query) => response.query(query),
-        None => response,
-    };
-    let response = response
-        .send()
+impl ApiClient {
+    pub async fn reset(&self) {
+        *self.client.write().await = None;
+        *self.stream_client.write().await = None;
+        self.reset_signal.notify_waiters();
+    }
+
+    // Resolves on the next `reset`, i.e. when the account, server or network
+    // settings change under a long lived connection.
+    pub fn on_reset(&self) -> Notified<'_> {
+        self.reset_signal.notified()
+    }
+
+    pub async fn lock_outbox(&self) -> MutexGuard<'_, ()> {
+        self.outbox.lock().await
+    }
+
+    pub async fn cancellable<T, F>(&self, request_id: Option<String>, future: F) -> Result<T, Error>
+    where
+        F: Future<Output = Result<T, Error>>,
+    {
+        let Some(request_id) = request_id else {
+            return future.await;
+        };
+        let cancel = Arc::new(Notify::new());
+        self.in_flight
+            .lock()
+            .await
+            .insert(request_id.clone(), cancel.clone());
+        let result = tokio::select! {
+            result = future => result,
+            _ = cancel.notified() => Err(Error::Cancelled),
+        };
+        self.in_flight.lock().await.remove(&request_id);
+        result
+    }
+
+    pub async fn cancel(&self, request_id: &str) -> bool {
+        match self.in_flight.lock().await.get(request_id) {
+            Some(cancel) => {
+                cancel.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    async fn client(&self, data_dir: &Path, meta: &Meta) -> Result<Client, Error> {
+        cached_client(&self.client, || {
+            build_client(data_dir, meta.cert_fingerprint.clone())
+        })
         .await
-        .map_err(|e| Error::Server(e.to_string()))?;
+    }
 
-    match response.status() {
-        StatusCode::OK => {
-            let response = response
-                .json::<T>()
-                .await
-                .map_err(|e| Error::Client(e.to_string()))?;
-            Ok(response)
-        }
-        StatusCode::UNAUTHORIZED => Err(Error::Server("Unauthorized".to_string())),
-        StatusCode::NETWORK_AUTHENTICATION_REQUIRED => {
-            let refresh_token = meta.refresh_token;
-            let new_tokens = client
-                .post(format!("http://{}/auth/refresh", meta.server_ip))
-                .body(refresh_token)
-                .send()
-                .await
-                .map_err(|e| Error::Server(e.to_string()))?;
-
-            let new_tokens = match new_tokens.status() {
-                StatusCode::OK => new_tokens
-                    .json::<LoginResponse>()
-                    .await
-                    .map_err(|e| Error::Client(e.to_string()))?,
-                StatusCode::UNAUTHORIZED => {
-                    return Err(Error::Server("Unauthorized".to_string()));
+    async fn stream_client(&self, data_dir: &Path, meta: &Meta) -> Result<Client, Error> {
+        cached_client(&self.stream_client, || {
+            build_stream_client(data_dir, meta.cert_fingerprint.clone())
+        })
+        .await
+    }
+
+    pub async fn make_request<B>(
+        &self,
+        method: Method,
+        url_path: &str,
+        data_dir: &Path,
+        query: Option<&[(&str, &str)]>,
+        body: Option<&B>,
+        headers: HeaderMap,
+    ) -> Result<Response, Error>
+    where
+        B: Serialize + ?Sized,
+    {
+        let meta = get_meta_internal(data_dir).await?;
+        let client = self.client(data_dir, &meta).await?;
+        let meta = if meta.access_token_expiring(TOKEN_REFRESH_MARGIN) {
+            self.refresh_tokens(&client, meta, data_dir).await?
+        } else {
+            meta
+        };
+        let response = send(
+            &client,
+            method.clone(),
+            &meta,
+            url_path,
+            query,
+            body,
+            headers.clone(),
+        )
+        .await?;
+        if response.status() != StatusCode::NETWORK_AUTHENTICATION_REQUIRED {
+            return Ok(response);
+        }
+
+        let meta = self.refresh_tokens(&client, meta, data_dir).await?;
+        send(&client, method, &meta, url_path, query, body, headers).await
+    }
+
+    // Opens a long lived `text/event-stream` response, refreshing the tokens
+    // the same way as `make_request` does.
+    pub async fn open_stream(&self, url_path: &str, data_dir: &Path) -> Result<Response, Error> {
+        let meta = get_meta_internal(data_dir).await?;
+        let client = self.stream_client(data_dir, &meta).await?;
+        let meta = if meta.access_token_expiring(TOKEN_REFRESH_MARGIN) {
+            self.refresh_tokens(&client, meta, data_dir).await?
+        } else {
+            meta
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static("text/event-stream"));
+        let response = send::<()>(
+            &client,
+            Method::GET,
+            &meta,
+            url_path,
+            None,
+            None,
+            headers.clone(),
+        )
+        .await?;
+        if response.status() != StatusCode::NETWORK_AUTHENTICATION_REQUIRED {
+            return ensure_success(response).await;
+        }
+
+        let meta = self.refresh_tokens(&client, meta, data_dir).await?;
+        let response =
+            send::<()>(&client, Method::GET, &meta, url_path, None, None, headers).await?;
+        ensure_success(response).await
+    }
+
+    pub async fn make_get_request<T>(
+        &self,
+        url_path: &str,
+        data_dir: &Path,
+        query: Option<&[(&str, &str)]>,
+    ) -> Result<T, Error>
+    where
+        T: DeserializeOwned,
+    {
+        self.try_replay_outbox(data_dir).await;
+        let settings = get_network_settings_internal(data_dir)?;
+        let mut attempt = 0;
+        loop {
+            match self.make_get_request_once(url_path, data_dir, query).await {
+                Err(e) if e.retryable() && attempt < settings.max_retries => {
+                    log::warn!("GET {} failed, retrying: {}", url_path, e);
+                    tokio::time::sleep(backoff_delay(attempt)).await;
+                    attempt += 1;
                 }
-                StatusCode::NETWORK_AUTHENTICATION_REQUIRED => {
-                    return Err(Error::LoginExpired);
+                result => return result,
+            }
+        }
+    }
+
+    // Revalidates against the stored ETag / Last-Modified when there is one,
+    // answering a `304 Not Modified` from the stored body.
+    async fn make_get_request_once<T>(
+        &self,
+        url_path: &str,
+        data_dir: &Path,
+        query: Option<&[(&str, &str)]>,
+    ) -> Result<T, Error>
+    where
+        T: DeserializeOwned,
+    {
+        let key = cache_key(url_path, query.unwrap_or_default());
+        let validated = read_validated(data_dir, &key);
+        let mut headers = HeaderMap::new();
+        if let Some(validated) = &validated {
+            if let Some(etag) = validated
+                .etag
+                .as_deref()
+                .and_then(|etag| HeaderValue::from_str(etag).ok())
+            {
+                headers.insert(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = validated
+                .last_modified
+                .as_deref()
+                .and_then(|last_modified| HeaderValue::from_str(last_modified).ok())
+            {
+                headers.insert(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        let response = self
+            .make_request::<()>(Method::GET, url_path, data_dir, query, None, headers)
+            .await?;
+        if let (StatusCode::NOT_MODIFIED, Some(validated)) = (response.status(), validated) {
+            return serde_json::from_value(validated.body)
+                .map_err(|e| Error::Decode(e.to_string()));
+        }
+
+        let response = ensure_success(response).await?;
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value: &HeaderValue| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let etag = header(ETAG);
+        let last_modified = header(LAST_MODIFIED);
+        let body: Value = response.json().await.map_err(|e| {
+            if e.is_timeout() {
+                Error::Timeout(e.to_string())
+            } else {
+                Error::Decode(e.to_string())
+            }
+        })?;
+        if etag.is_some() || last_modified.is_some() {
+            let validated = ValidatedResponse {
+                etag,
+                last_modified,
+                body,
+            };
+            // Losing the validators only costs a full download next time.
+            let _ = write_validated(data_dir, &key, &validated);
+            return serde_json::from_value(validated.body)
+                .map_err(|e| Error::Decode(e.to_string()));
+        }
+        serde_json::from_value(body).map_err(|e| Error::Decode(e.to_string()))
+    }
+
+    // Mutations that cannot reach the server are queued in the outbox and
+    // replayed in order, so they are only sent directly when nothing is
+    // waiting ahead of them.
+    pub async fn make_queued_post_request<T>(
+        &self,
+        url_path: &str,
+        data_dir: &Path,
+        data: &T,
+    ) -> Result<(), Error>
+    where
+        T: Serialize,
+    {
+        // Taken before the first attempt, which may take up to the connect and
+        // read timeouts to give up.
+        let queued_at = Local::now();
+        let _outbox = self.lock_outbox().await;
+        let mut pending = read_outbox(data_dir)?;
+        let mut unreachable = false;
+        if pending.iter().all(|write| write.parked) {
+            match self.post_write(url_path, data_dir, data, queued_at).await {
+                // Anything past the connect may already have been applied, and
+                // sending it again would apply it twice.
+                Err(Error::Network(_)) => unreachable = true,
+                result => return result,
+            }
+        }
+
+        let body = serde_json::to_value(data).map_err(|e| Error::Client(e.to_string()))?;
+        let id = pending.iter().map(|write| write.id).max().unwrap_or(0) + 1;
+        pending.push(PendingWrite {
+            id,
+            url_path: url_path.to_string(),
+            body,
+            queued_at,
+            last_error: None,
+            parked: false,
+        });
+        log::info!("Queued write to {} for later", url_path);
+        write_outbox(data_dir, &pending)?;
+        // The server just refused the connection, so trying again right away
+        // would only wait out another connect timeout. The background replay
+        // picks it up once the server is back.
+        if unreachable {
+            return Ok(());
+        }
+        // Still being unreachable is what the queue is for, but a write the
+        // server refused has to be reported.
+        let parked = self.replay_outbox(data_dir).await.unwrap_or_default();
+        match parked.into_iter().find(|(parked_id, _)| *parked_id == id) {
+            Some((_, e)) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    // Concurrent requests that hit an expired token queue up here, and only
+    // the first one actually posts the refresh token. The rest see the new
+    // tokens on disk and retry with those.
+    async fn refresh_tokens(
+        &self,
+        client: &Client,
+        expired: Meta,
+        data_dir: &Path,
+    ) -> Result<Meta, Error> {
+        let _refresh = self.refresh.lock().await;
+        let meta = get_meta_internal(data_dir).await?;
+        if meta.access_token != expired.access_token {
+            return Ok(meta);
+        }
+        log::info!("Refreshing access token");
+        request_new_tokens(client, meta, data_dir).await
+    }
+
+    async fn try_replay_outbox(&self, data_dir: &Path) {
+        let Ok(_outbox) = self.outbox.try_lock() else {
+            return;
+        };
+        if read_outbox(data_dir).is_ok_and(|pending| !pending.is_empty()) {
+            let _ = self.replay_outbox(data_dir).await;
+        }
+    }
+
+    // Sends the pending writes in order, stopping at the first one that has to
+    // wait for the server or a new login. Writes that fail for any other
+    // reason are parked with their error, so they no longer hold up the rest,
+    // and returned. Callers must hold the outbox lock.
+    async fn replay_outbox(&self, data_dir: &Path) -> Result<Vec<(u64, Error)>, Error> {
+        let mut pending = read_outbox(data_dir)?;
+        let mut parked = Vec::new();
+        while let Some(idx) = pending.iter().position(|write| !write.parked) {
+            let write = &mut pending[idx];
+            let result = self
+                .post_write(&write.url_path, data_dir, &write.body, write.queued_at)
+                .await;
+            match result {
+                Ok(()) => {
+                    pending.remove(idx);
                 }
-                _ => {
-                    return Err(Error::Client(format!(
-                        "Unknown error: {}",
-                        response.status()
-                    )));
+                Err(e) => {
+                    log::warn!("Replaying {} failed: {}", write.url_path, e);
+                    write.last_error = Some(e.to_string());
+                    if never_delivered(&e) {
+                        write_outbox(data_dir, &pending)?;
+                        return Err(e);
+                    }
+                    write.parked = true;
+                    parked.push((write.id, e));
                 }
-            };
+            }
+            write_outbox(data_dir, &pending)?;
+        }
+        Ok(parked)
+    }
 
-            let meta = Meta {
-                username: meta.username,
-                server_ip: meta.server_ip,
-                access_token: new_tokens.access_token,
-                refresh_token: new_tokens.refresh_token,
-            };
+    // Sends a mutation stamped with the time the user made it.
+    async fn post_write<T>(
+        &self,
+        url_path: &str,
+        data_dir: &Path,
+        data: &T,
+        made_at: DateTime<Local>,
+    ) -> Result<(), Error>
+    where
+        T: Serialize,
+    {
+        let mut headers = HeaderMap::new();
+        let timestamp = HeaderValue::from_str(&made_at.to_rfc3339())
+            .map_err(|e| Error::Client(e.to_string()))?;
+        headers.insert(CLIENT_TIMESTAMP_HEADER, timestamp);
+        let response = self
+            .make_request(Method::POST, url_path, data_dir, None, Some(data), headers)
+            .await?;
+        ensure_success(response).await?;
+        Ok(())
+    }
+}
 
-            let meta_json =
-                serde_json::to_string(&meta).map_err(|e| Error::Client(e.to_string()))?;
-            let meta_path = data_dir.join("meta.json");
-            std::fs::write(meta_path, meta_json).map_err(|e| Error::Client(e.to_string()))?;
+// A write that failed like this was not applied, and can be sent again once
+// the server is reachable or the user has logged in again.
+fn never_delivered(e: &Error) -> bool {
+    matches!(
+        e,
+        Error::Network(_) | Error::Unauthorized | Error::LoginExpired | Error::Locked
+    )
+}
 
-            let response = client
-                .get(format!("http://{}{}", meta.server_ip, url_path))
-                .header(AUTHORIZATION, format!("Bearer {}", meta.access_token))
-                .send()
-                .await
-                .map_err(|e| Error::Server(e.to_string()))?
-                .json::<T>()
-                .await
-                .map_err(|e| Error::Client(e.to_string()))?;
+async fn cached_client<F, Fut>(slot: &RwLock<Option<Client>>, build: F) -> Result<Client, Error>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<Client, Error>>,
+{
+    if let Some(client) = slot.read().await.as_ref() {
+        return Ok(client.clone());
+    }
+    let mut client = slot.write().await;
+    if let Some(client) = client.as_ref() {
+        return Ok(client.clone());
+    }
+    let new_client = build().await?;
+    *client = Some(new_client.clone());
+    Ok(new_client)
+}
+
+async fn client_builder(data_dir: &Path) -> Result<ClientBuilder, Error> {
+    let settings = open_network_settings(data_dir).await?;
+    let builder =
+        Client::builder().connect_timeout(Duration::from_secs(settings.connect_timeout_secs));
+    apply_proxy(builder, &settings.proxy)
+}
+
+pub async fn build_client(
+    data_dir: &Path,
+    cert_fingerprint: Option<String>,
+) -> Result<Client, Error> {
+    let settings = get_network_settings_internal(data_dir)?;
+    let tls = TlsSettings::load(data_dir, cert_fingerprint)?;
+    apply_tls(client_builder(data_dir).await?, &tls)?
+        .read_timeout(Duration::from_secs(settings.read_timeout_secs))
+        .build()
+        .map_err(|e| Error::Client(e.to_string()))
+}
+
+// Event streams sit idle between events, so they only get a connect timeout.
+pub async fn build_stream_client(
+    data_dir: &Path,
+    cert_fingerprint: Option<String>,
+) -> Result<Client, Error> {
+    let tls = TlsSettings::load(data_dir, cert_fingerprint)?;
+    apply_tls(client_builder(data_dir).await?, &tls)?
+        .build()
+        .map_err(|e| Error::Client(e.to_string()))
+}
+
+// For third party services, which go through the proxy but not through the
+// certificate pinning meant for the scheduler server.
+pub async fn build_external_client(data_dir: &Path) -> Result<Client, Error> {
+    let settings = get_network_settings_internal(data_dir)?;
+    client_builder(data_dir)
+        .await?
+        .read_timeout(Duration::from_secs(settings.read_timeout_secs))
+        .build()
+        .map_err(|e| Error::Client(e.to_string()))
+}
 
-            Ok(response)
+// Only failures to connect are `Network`, past that point the server may
+// already have received the request. A rejected certificate is not going to
+// pass on a retry either.
+pub fn map_send_error(e: reqwest::Error) -> Error {
+    if let Some(tls_error) = tls_error(&e) {
+        Error::Tls(tls_error.to_string())
+    } else if e.is_connect() {
+        Error::Network(e.to_string())
+    } else if e.is_timeout() {
+        Error::Timeout(e.to_string())
+    } else {
+        Error::Interrupted(e.to_string())
+    }
+}
+
+// rustls errors reach reqwest wrapped in `io::Error`s, whose `source` skips
+// straight past what they wrap.
+fn tls_error(e: &reqwest::Error) -> Option<&rustls::Error> {
+    let mut source = e.source();
+    while let Some(error) = source {
+        let mut unwrapped: &(dyn std::error::Error + 'static) = error;
+        while let Some(wrapped) = unwrapped
+            .downcast_ref::<std::io::Error>()
+            .and_then(std::io::Error::get_ref)
+        {
+            unwrapped = wrapped;
+        }
+        if let Some(tls_error) = unwrapped.downcast_ref::<rustls::Error>() {
+            return Some(tls_error);
         }
-        _ => Err(Error::Client(format!(
-            "Unknown error: {}",
-            response.status()
-        ))),
+        source = error.source();
     }
+    None
 }
 
-pub async fn make_post_request<T>(url_path: &str, data_dir: &Path, data: &T) -> Result<(), Error>
+pub fn backoff_delay(attempt: u32) -> Duration {
+    Duration::from_millis(500 * 2u64.pow(attempt.min(4)))
+}
+
+async fn send<B>(
+    client: &Client,
+    method: Method,
+    meta: &Meta,
+    url_path: &str,
+    query: Option<&[(&str, &str)]>,
+    body: Option<&B>,
+    headers: HeaderMap,
+) -> Result<Response, Error>
 where
-    T: serde::Serialize,
+    B: Serialize + ?Sized,
 {
-    let meta = get_meta_internal(data_dir).await?;
+    let request = client
+        .request(method, meta.server.url(url_path))
+        .headers(headers)
+        .header(AUTHORIZATION, format!("Bearer {}", meta.access_token));
+    let request = match query {
+        Some(query) => request.query(query),
+        None => request,
+    };
+    let request = match body {
+        Some(body) => request.json(body),
+        None => request,
+    };
+    request.send().await.map_err(map_send_error)
+}
 
-    let client = Client::new();
+async fn request_new_tokens(client: &Client, meta: Meta, data_dir: &Path) -> Result<Meta, Error> {
     let response = client
-        .post(format!("http://{}{}", meta.server_ip, url_path))
-        .json(data)
-        .header(AUTHORIZATION, format!("Bearer {}", meta.access_token));
-    let response = response
+        .post(meta.server.url("/auth/refresh"))
+        .body(meta.refresh_token)
         .send()
         .await
-        .map_err(|e| Error::Server(e.to_string()))?;
+        .map_err(map_send_error)?;
+
+    let new_tokens = ensure_success(response)
+        .await?
+        .json::<LoginResponse>()
+        .await
+        .map_err(|e| Error::Decode(e.to_string()))?;
 
+    let meta = Meta {
+        username: meta.username,
+        server: meta.server,
+        cert_fingerprint: meta.cert_fingerprint,
+        access_expires_at: new_tokens.access_expires_at(),
+        access_token: new_tokens.access_token,
+        refresh_token: new_tokens.refresh_token,
+        server_info: meta.server_info,
+    };
+    save_meta_internal(data_dir, &meta).await?;
+    Ok(meta)
+}
+
+pub async fn ensure_success(response: Response) -> Result<Response, Error> {
     match response.status() {
-        StatusCode::OK => Ok(()),
-        StatusCode::UNAUTHORIZED => Err(Error::Server("Unauthorized".to_string())),
-        StatusCode::NETWORK_AUTHENTICATION_REQUIRED => {
-            let refresh_token = meta.refresh_token;
-            let new_tokens = client
-                .post(format!("http://{}/auth/refresh", meta.server_ip))
-                .body(refresh_token)
-                .send()
-                .await
-                .map_err(|e| Error::Server(e.to_string()))?;
-
-            let new_tokens = match new_tokens.status() {
-                StatusCode::OK => new_tokens
-                    .json::<LoginResponse>()
-                    .await
-                    .map_err(|e| Error::Client(e.to_string()))?,
-                StatusCode::UNAUTHORIZED => {
-                    return Err(Error::Server("Unauthorized".to_string()));
-                }
-                StatusCode::NETWORK_AUTHENTICATION_REQUIRED => {
-                    return Err(Error::LoginExpired);
-                }
-                _ => {
-                    return Err(Error::Client(format!(
-                        "Unknown error: {}",
-                        response.status()
-                    )));
-                }
-            };
+        status if status.is_success() => Ok(response),
+        StatusCode::UNAUTHORIZED => Err(Error::Unauthorized),
+        StatusCode::NETWORK_AUTHENTICATION_REQUIRED => Err(Error::LoginExpired),
+        status => {
+            let body = response.text().await.ok().filter(|body| !body.is_empty());
+            Err(Error::Http {
+                status: status.as_u16(),
+                body,
+            })
+        }
+    }
+}
 
-            let meta = Meta {
-                username: meta.username,
-                server_ip: meta.server_ip,
-                access_token: new_tokens.access_token,
-                refresh_token: new_tokens.refresh_token,
-            };
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use serde_json::{json, Value};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::{
+        datatypes::HomeData,
+        fake_server::{Fixture, Scripted, TempDir},
+        tauface::outbox::read_outbox,
+    };
+
+    #[tokio::test]
+    async fn get_request_sends_access_token() {
+        let fixture = Fixture::logged_in().await;
+        let home_data: HomeData = fixture
+            .client
+            .make_get_request("/state", fixture.data_dir(), None)
+            .await
+            .unwrap();
+        assert_eq!(home_data.currentblock.current_block_name, "Writing tests");
+        let request = fixture.server.requests_to("/state").pop().unwrap();
+        assert_eq!(request.headers["authorization"], "Bearer access-1");
+    }
+
+    #[tokio::test]
+    async fn unchanged_responses_are_revalidated_with_etags() {
+        let fixture = Fixture::logged_in().await;
+        let fetch = || {
+            fixture
+                .client
+                .make_get_request::<HomeData>("/state", fixture.data_dir(), None)
+        };
+        let first = fetch().await.unwrap();
+        let second = fetch().await.unwrap();
+        assert_eq!(first, second);
+
+        let requests = fixture.server.requests_to("/state");
+        assert!(!requests[0].headers.contains_key("if-none-match"));
+        assert!(requests[1].headers.contains_key("if-none-match"));
+
+        let mut home_data = crate::fake_server::sample_home_data();
+        home_data["currentblock"]["current_block_name"] = json!("Changed");
+        fixture.server.set_home_data(home_data);
+        let third = fetch().await.unwrap();
+        assert_eq!(third.currentblock.current_block_name, "Changed");
+    }
+
+    #[tokio::test]
+    async fn expired_token_is_refreshed_and_request_replayed() {
+        let fixture = Fixture::logged_in().await;
+        fixture.server.expire_access_token();
+        let _: HomeData = fixture
+            .client
+            .make_get_request("/state", fixture.data_dir(), None)
+            .await
+            .unwrap();
+
+        assert_eq!(fixture.server.refresh_count(), 1);
+        let requests = fixture.server.requests_to("/state");
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            requests[1].headers["authorization"],
+            format!("Bearer {}", fixture.server.access_token())
+        );
+        let meta = get_meta_internal(fixture.data_dir()).await.unwrap();
+        assert_eq!(meta.access_token, fixture.server.access_token());
+    }
+
+    #[tokio::test]
+    async fn expired_post_is_replayed_with_the_same_body() {
+        let fixture = Fixture::logged_in().await;
+        fixture.server.expire_access_token();
+        let body = json!({ "block_type_id": 2, "current_block_name": "Reading" });
+        fixture
+            .client
+            .make_queued_post_request("/timeblock/next", fixture.data_dir(), &body)
+            .await
+            .unwrap();
+
+        let requests = fixture.server.requests_to("/timeblock/next");
+        assert_eq!(requests.len(), 2);
+        let replayed: Value = serde_json::from_str(&requests[1].body).unwrap();
+        assert_eq!(replayed, body);
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_share_one_refresh() {
+        let fixture = Fixture::logged_in().await;
+        fixture.server.expire_access_token();
+        let fetch = || {
+            fixture
+                .client
+                .make_get_request::<HomeData>("/state", fixture.data_dir(), None)
+        };
+        let (first, second, third) = tokio::join!(fetch(), fetch(), fetch());
+        assert!(first.is_ok() && second.is_ok() && third.is_ok());
+        assert_eq!(fixture.server.refresh_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn token_close_to_expiry_is_refreshed_up_front() {
+        let fixture = Fixture::logged_out().await;
+        fixture.server.set_expires_in(30);
+        fixture.login().await.unwrap();
+        let _: HomeData = fixture
+            .client
+            .make_get_request("/state", fixture.data_dir(), None)
+            .await
+            .unwrap();
+
+        assert_eq!(fixture.server.refresh_count(), 1);
+        assert_eq!(fixture.server.requests_to("/state").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rejected_refresh_token_requires_relogin() {
+        let fixture = Fixture::logged_in().await;
+        fixture.server.expire_access_token();
+        fixture.server.script(
+            "/auth/refresh",
+            Scripted::Respond {
+                status: 401,
+                body: Value::Null,
+            },
+        );
+        let error = fixture
+            .client
+            .make_get_request::<HomeData>("/state", fixture.data_dir(), None)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::Unauthorized));
+        assert_eq!(error.action(), Some(crate::error::RecoveryAction::Relogin));
+    }
 
-            let meta_json =
-                serde_json::to_string(&meta).map_err(|e| Error::Client(e.to_string()))?;
-            let meta_path = data_dir.join("meta.json");
-            std::fs::write(meta_path, meta_json).map_err(|e| Error::Client(e.to_string()))?;
+    #[tokio::test]
+    async fn server_errors_are_retried_for_gets() {
+        let fixture = Fixture::logged_in().await;
+        fixture.server.script(
+            "/state",
+            Scripted::Respond {
+                status: 503,
+                body: Value::Null,
+            },
+        );
+        let _: HomeData = fixture
+            .client
+            .make_get_request("/state", fixture.data_dir(), None)
+            .await
+            .unwrap();
+        assert_eq!(fixture.server.requests_to("/state").len(), 2);
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_not_retried() {
+        let fixture = Fixture::logged_in().await;
+        fixture.server.script(
+            "/state",
+            Scripted::Respond {
+                status: 400,
+                body: json!("bad request"),
+            },
+        );
+        let error = fixture
+            .client
+            .make_get_request::<HomeData>("/state", fixture.data_dir(), None)
+            .await
+            .unwrap_err();
+        assert_eq!(error.status(), Some(400));
+        assert!(!error.retryable());
+        assert_eq!(fixture.server.requests_to("/state").len(), 1);
+    }
 
-            let response = client
-                .post(format!("http://{}{}", meta.server_ip, url_path))
-                .header(AUTHORIZATION, format!("Bearer {}", meta.access_token))
-                .json(data)
-                .send()
+    #[tokio::test]
+    async fn unreachable_writes_are_queued_and_replayed_in_order() {
+        let fixture = Fixture::logged_in().await;
+        fixture.server.go_offline().await;
+        for n in 1..=2 {
+            fixture
+                .client
+                .make_queued_post_request("/timeblock/next", fixture.data_dir(), &json!({ "n": n }))
                 .await
-                .map_err(|e| Error::Server(e.to_string()))?;
-            match response.status() {
-                StatusCode::OK => Ok(()),
-                _ => Err(Error::Server("Unknown error".to_string())),
+                .unwrap();
+            // Only queued, not retried against the server that just failed.
+            if n == 1 {
+                let pending = read_outbox(fixture.data_dir()).unwrap();
+                assert!(pending[0].last_error.is_none());
             }
         }
-        _ => Err(Error::Client(format!(
-            "Unknown error: {}",
-            response.status()
-        ))),
+
+        let pending = read_outbox(fixture.data_dir()).unwrap();
+        assert_eq!(pending.len(), 2);
+        assert!(pending[0].last_error.is_some());
+
+        fixture.server.go_online().await;
+        let _: HomeData = fixture
+            .client
+            .make_get_request("/state", fixture.data_dir(), None)
+            .await
+            .unwrap();
+        assert!(read_outbox(fixture.data_dir()).unwrap().is_empty());
+
+        let delivered = fixture.server.requests_to("/timeblock/next");
+        let bodies: Vec<Value> = delivered
+            .iter()
+            .map(|request| serde_json::from_str(&request.body).unwrap())
+            .collect();
+        assert_eq!(bodies, [json!({ "n": 1 }), json!({ "n": 2 })]);
+        assert_eq!(
+            delivered[0].headers[CLIENT_TIMESTAMP_HEADER],
+            pending[0].queued_at.to_rfc3339()
+        );
+    }
+
+    #[tokio::test]
+    async fn writes_that_may_have_arrived_are_not_queued() {
+        let fixture = Fixture::logged_in().await;
+        fixture
+            .server
+            .script("/timeblock/next", Scripted::Disconnect);
+        let error = fixture
+            .client
+            .make_queued_post_request("/timeblock/next", fixture.data_dir(), &json!({ "n": 1 }))
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::Interrupted(_)));
+        assert!(read_outbox(fixture.data_dir()).unwrap().is_empty());
+
+        let request = fixture.server.requests_to("/timeblock/next").pop().unwrap();
+        assert!(request.headers.contains_key(CLIENT_TIMESTAMP_HEADER));
+    }
+
+    #[tokio::test]
+    async fn refused_writes_are_parked_instead_of_blocking_the_queue() {
+        let fixture = Fixture::logged_in().await;
+        fixture.server.go_offline().await;
+        for n in 1..=2 {
+            fixture
+                .client
+                .make_queued_post_request("/timeblock/next", fixture.data_dir(), &json!({ "n": n }))
+                .await
+                .unwrap();
+        }
+        fixture.server.go_online().await;
+        fixture.server.script(
+            "/timeblock/next",
+            Scripted::Respond {
+                status: 400,
+                body: json!("block overlaps"),
+            },
+        );
+        let error = fixture
+            .client
+            .make_queued_post_request("/timeblock/next", fixture.data_dir(), &json!({ "n": 3 }))
+            .await;
+        // The new write went through, the refused one is kept for the user.
+        assert!(error.is_ok());
+        let pending = read_outbox(fixture.data_dir()).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert!(pending[0].parked);
+        assert_eq!(pending[0].body, json!({ "n": 1 }));
+
+        // Later writes go straight out again.
+        fixture.server.script(
+            "/timeblock/next",
+            Scripted::Respond {
+                status: 400,
+                body: json!("block overlaps"),
+            },
+        );
+        let error = fixture
+            .client
+            .make_queued_post_request("/timeblock/next", fixture.data_dir(), &json!({ "n": 4 }))
+            .await
+            .unwrap_err();
+        assert_eq!(error.status(), Some(400));
+        assert_eq!(read_outbox(fixture.data_dir()).unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn tls_failures_are_not_retried() {
+        // Answers the handshake in plain text, like a server without TLS.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let _ = stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await;
+                let _ = stream.read_to_end(&mut Vec::new()).await;
+            }
+        });
+        let data_dir = TempDir::new();
+        let client = build_client(data_dir.path(), None).await.unwrap();
+        let error = client
+            .get(format!("https://{}/version", address))
+            .send()
+            .await
+            .map_err(map_send_error)
+            .unwrap_err();
+        assert!(matches!(error, Error::Tls(_)));
+        assert!(!error.retryable());
+        assert_eq!(
+            error.action(),
+            Some(crate::error::RecoveryAction::CheckServerAddress)
+        );
+    }
+
+    #[tokio::test]
+    async fn cancelled_requests_return_cancelled() {
+        let fixture = Fixture::logged_in().await;
+        let request_id = "cancel-me".to_string();
+        let cancel = async {
+            while !fixture.client.cancel(&request_id).await {
+                tokio::task::yield_now().await;
+            }
+        };
+        let request = fixture.client.cancellable(
+            Some(request_id.clone()),
+            std::future::pending::<Result<(), Error>>(),
+        );
+        let (result, _) = tokio::join!(request, cancel);
+        assert!(matches!(result, Err(Error::Cancelled)));
     }
 }
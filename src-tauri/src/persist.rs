@@ -0,0 +1,249 @@
+// Crash safe storage for the JSON state files. Writes go to a temp file that
+// is synced and renamed over the target, under an advisory lock shared with
+// readers, and the previous good copy is kept as `<name>.bak` so a file
+// damaged outside of this layer can still be recovered.
+
+use std::{
+    fs::File,
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::{
+    error::Error,
+    schema::{Envelope, Schema},
+};
+
+const BACKUP: &str = "bak";
+const TEMP: &str = "tmp";
+const LOCK: &str = "lock";
+
+pub fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T, Error> {
+    let backup_path = sibling(path, BACKUP);
+    if !path.exists() && !backup_path.exists() {
+        return Err(Error::Io(format!("{} does not exist", path.display())));
+    }
+    let contents = {
+        let _lock = lock(path, false)?;
+        std::fs::read(path)
+    };
+    let error = match contents {
+        Ok(contents) => match serde_json::from_slice(&contents) {
+            Ok(value) => return Ok(value),
+            Err(e) => Error::Decode(e.to_string()),
+        },
+        Err(e) => Error::Io(e.to_string()),
+    };
+    recover(path).ok_or(error)
+}
+
+// Reads a state file at the current schema version, writing back the
+// upgraded copy when it was older.
+pub fn read_versioned<T: DeserializeOwned>(path: &Path, schema: &Schema) -> Result<T, Error> {
+    let stored: Value = read_json(path)?;
+    let (data, upgraded) = schema.upgrade(stored)?;
+    if upgraded {
+        write_versioned(path, schema, &data)?;
+        log::info!(
+            "Upgraded {} to schema version {}",
+            schema.name,
+            schema.current_version()
+        );
+    }
+    serde_json::from_value(data).map_err(|e| Error::Decode(format!("{}: {}", schema.name, e)))
+}
+
+// Like `read_versioned`, but leaves an older file as it is.
+pub fn peek_versioned<T: DeserializeOwned>(path: &Path, schema: &Schema) -> Result<T, Error> {
+    let stored: Value = read_json(path)?;
+    let (data, _) = schema.upgrade(stored)?;
+    serde_json::from_value(data).map_err(|e| Error::Decode(format!("{}: {}", schema.name, e)))
+}
+
+pub fn write_versioned<T: Serialize + ?Sized>(
+    path: &Path,
+    schema: &Schema,
+    data: &T,
+) -> Result<(), Error> {
+    let envelope = Envelope {
+        schema_version: schema.current_version(),
+        data,
+    };
+    write_json(path, &envelope)
+}
+
+pub fn write_json<T: Serialize + ?Sized>(path: &Path, value: &T) -> Result<(), Error> {
+    let contents = serde_json::to_vec(value).map_err(|e| Error::Client(e.to_string()))?;
+    write(path, &contents)
+}
+
+pub fn write(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| Error::Io(e.to_string()))?;
+    }
+    let _lock = lock(path, true)?;
+    // Only a copy that still parses is worth keeping.
+    if let Ok(previous) = std::fs::read(path) {
+        if serde_json::from_slice::<serde::de::IgnoredAny>(&previous).is_ok() {
+            replace(&sibling(path, BACKUP), &previous).map_err(|e| Error::Io(e.to_string()))?;
+        }
+    }
+    replace(path, contents).map_err(|e| Error::Io(e.to_string()))
+}
+
+pub fn remove(path: &Path) -> Result<(), Error> {
+    for path in [
+        path.to_path_buf(),
+        sibling(path, BACKUP),
+        sibling(path, LOCK),
+    ] {
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(Error::Io(e.to_string())),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// Drops the last good copy, for when it holds something that must not stay on
+// disk.
+pub fn discard_backup(path: &Path) -> Result<(), Error> {
+    let _lock = lock(path, true)?;
+    match std::fs::remove_file(sibling(path, BACKUP)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(Error::Io(e.to_string())),
+        _ => Ok(()),
+    }
+}
+
+// Falls back to the last good copy and puts it back in place.
+fn recover<T: DeserializeOwned>(path: &Path) -> Option<T> {
+    let _lock = lock(path, true).ok()?;
+    let backup = std::fs::read(sibling(path, BACKUP)).ok()?;
+    let value = serde_json::from_slice(&backup).ok()?;
+    log::warn!(
+        "{} was unreadable, restored it from the backup",
+        path.display()
+    );
+    if let Err(e) = replace(path, &backup) {
+        log::warn!("Could not restore {}: {}", path.display(), e);
+    }
+    Some(value)
+}
+
+fn replace(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let temp_path = sibling(path, TEMP);
+    let mut file = File::create(&temp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    std::fs::rename(&temp_path, path)?;
+    sync_parent(path)
+}
+
+// Makes the rename itself durable.
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> std::io::Result<()> {
+    match path.parent() {
+        Some(parent) => File::open(parent)?.sync_all(),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+// The lock is released when the returned file is dropped.
+fn lock(path: &Path, exclusive: bool) -> Result<File, Error> {
+    let lock_file = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(sibling(path, LOCK))
+        .map_err(|e| Error::Io(e.to_string()))?;
+    let locked = if exclusive {
+        lock_file.lock()
+    } else {
+        lock_file.lock_shared()
+    };
+    locked.map_err(|e| Error::Io(e.to_string()))?;
+    Ok(lock_file)
+}
+
+fn sibling(path: &Path, extension: &str) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(extension);
+    path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::fake_server::TempDir;
+
+    #[test]
+    fn truncated_file_is_recovered_from_backup() {
+        let dir = TempDir::new();
+        let path = dir.path().join("state.json");
+        write_json(&path, &json!({ "version": 1 })).unwrap();
+        write_json(&path, &json!({ "version": 2 })).unwrap();
+
+        std::fs::write(&path, "{\"vers").unwrap();
+        let value: Value = read_json(&path).unwrap();
+        assert_eq!(value["version"], 1);
+        // The backup was put back in place.
+        let restored: Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(restored["version"], 1);
+
+        remove(&path).unwrap();
+        assert!(matches!(read_json::<Value>(&path), Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn corrupt_file_does_not_replace_good_backup() {
+        let dir = TempDir::new();
+        let path = dir.path().join("state.json");
+        write_json(&path, &json!({ "version": 1 })).unwrap();
+        write_json(&path, &json!({ "version": 2 })).unwrap();
+        std::fs::write(&path, "garbage").unwrap();
+        write_json(&path, &json!({ "version": 3 })).unwrap();
+
+        let backup: Value =
+            serde_json::from_slice(&std::fs::read(sibling(&path, BACKUP)).unwrap()).unwrap();
+        assert_eq!(backup["version"], 1);
+    }
+
+    #[test]
+    fn concurrent_writers_never_leave_a_torn_file() {
+        let dir = TempDir::new();
+        let path = dir.path().join("state.json");
+        let writers: Vec<_> = (0..8)
+            .map(|writer| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    for round in 0..20 {
+                        let padding = "x".repeat(1000 * writer);
+                        write_json(
+                            &path,
+                            &json!({ "writer": writer, "round": round, "padding": padding }),
+                        )
+                        .unwrap();
+                        read_json::<Value>(&path).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        let value: Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(value["round"], 19);
+    }
+}
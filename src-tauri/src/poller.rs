@@ -0,0 +1,137 @@
+use std::{path::Path, time::Duration};
+
+use chrono::Local;
+use tauri::{AppHandle, Emitter, Manager};
+use tokio::sync::Notify;
+
+use crate::{
+    cache,
+    datatypes::HomeData,
+    error::Error,
+    handshake::FEATURE_EVENTS,
+    netutils::ApiClient,
+    tauface::{
+        meta::get_meta_internal,
+        profiles::active_data_dir,
+        remote_iface::{fetch_home_data, history_key, HOME_DATA_KEY},
+        settings::get_network_settings_internal,
+    },
+};
+
+const MAIN_WINDOW: &str = "main";
+// Used while polling is disabled, so a changed setting is picked up.
+const IDLE_RECHECK: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+pub struct Poller {
+    wake: Notify,
+}
+
+impl Poller {
+    pub fn wake(&self) {
+        self.wake.notify_one();
+    }
+}
+
+// Polls `/state` for servers that cannot push events. Polling pauses while
+// the window is hidden and resumes immediately once it is focused again.
+pub fn spawn(app_handle: AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        let mut previous = None;
+        loop {
+            let client = app_handle.state::<ApiClient>();
+            // Registered before polling, so a reset during the poll is not
+            // missed.
+            let reset = client.on_reset();
+            tokio::pin!(reset);
+            reset.as_mut().enable();
+            let interval = match poll_interval(&app_handle) {
+                Some(interval) => {
+                    if window_visible(&app_handle) && !server_pushes(&app_handle).await {
+                        if let Ok(home_data) = poll(&app_handle, previous.as_ref()).await {
+                            previous = Some(home_data);
+                        }
+                    }
+                    interval
+                }
+                None => IDLE_RECHECK,
+            };
+            let poller = app_handle.state::<Poller>();
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                _ = poller.wake.notified() => {}
+                // The account or profile changed, and its data must not be
+                // diffed against the previous one's.
+                _ = &mut reset => previous = None,
+            }
+        }
+    });
+}
+
+fn poll_interval(app_handle: &AppHandle) -> Option<Duration> {
+    let data_dir = active_data_dir(app_handle).ok()?;
+    let settings = get_network_settings_internal(&data_dir).unwrap_or_default();
+    match settings.poll_interval_secs {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    }
+}
+
+// A minimized window still counts as visible to the window system. An
+// unfocused one stays on screen, so it is kept up to date.
+fn window_visible(app_handle: &AppHandle) -> bool {
+    app_handle
+        .get_webview_window(MAIN_WINDOW)
+        .is_some_and(|window| {
+            window.is_visible().unwrap_or(false) && !window.is_minimized().unwrap_or(false)
+        })
+}
+
+async fn server_pushes(app_handle: &AppHandle) -> bool {
+    let Ok(data_dir) = active_data_dir(app_handle) else {
+        return false;
+    };
+    get_meta_internal(&data_dir).await.is_ok_and(|meta| {
+        meta.server_info
+            .is_some_and(|server_info| server_info.supports(FEATURE_EVENTS))
+    })
+}
+
+async fn poll(app_handle: &AppHandle, previous: Option<&HomeData>) -> Result<HomeData, Error> {
+    let data_dir = active_data_dir(app_handle)?;
+    let client = app_handle.state::<ApiClient>();
+    let home_data = fetch_home_data(&client, &data_dir).await?;
+    if let Some(previous) = previous {
+        emit_changes(app_handle, &data_dir, previous, &home_data)?;
+    }
+    Ok(home_data)
+}
+
+fn emit_changes(
+    app_handle: &AppHandle,
+    data_dir: &Path,
+    previous: &HomeData,
+    current: &HomeData,
+) -> Result<(), Error> {
+    if previous == current {
+        return Ok(());
+    }
+    cache::mark_dirty(data_dir, HOME_DATA_KEY)?;
+    if previous.currentblock != current.currentblock {
+        app_handle
+            .emit("current-block-changed", &current.currentblock)
+            .map_err(|e| Error::Client(e.to_string()))?;
+    }
+    if previous.daydata != current.daydata {
+        cache::mark_dirty(data_dir, &history_key(Local::now().date_naive()))?;
+        app_handle
+            .emit("day-data-changed", &current.daydata)
+            .map_err(|e| Error::Client(e.to_string()))?;
+    }
+    if previous.blocktypes != current.blocktypes {
+        app_handle
+            .emit("block-types-changed", &current.blocktypes)
+            .map_err(|e| Error::Client(e.to_string()))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,240 @@
+use reqwest::{ClientBuilder, NoProxy, Proxy};
+use serde::{Deserialize, Serialize};
+
+use crate::{error::Error, secrets::Sealed};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyMode {
+    // Honour the HTTP_PROXY, HTTPS_PROXY, ALL_PROXY and NO_PROXY environment
+    // variables, which is what reqwest does when left alone.
+    #[default]
+    Environment,
+    // Connect directly, ignoring the environment.
+    Direct,
+    // Use the configured proxy only, ignoring the environment.
+    Manual,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyKind {
+    #[default]
+    Http,
+    Https,
+    Socks5,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct ProxySettings {
+    pub mode: ProxyMode,
+    pub kind: ProxyKind,
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // How `password` is kept in network.json. Not shown to the webview.
+    #[serde(skip_serializing)]
+    pub sealed_password: Option<Sealed>,
+    pub no_proxy: Vec<String>,
+}
+
+impl ProxySettings {
+    fn url(&self) -> Result<String, Error> {
+        let host = self.host.trim();
+        if host.is_empty() || self.port == 0 {
+            return Err(Error::InvalidInput(
+                "Proxy host and port are required".to_string(),
+            ));
+        }
+        let scheme = match self.kind {
+            ProxyKind::Http => "http",
+            ProxyKind::Https => "https",
+            // Resolve host names on the proxy, the client may not have DNS.
+            ProxyKind::Socks5 => "socks5h",
+        };
+        let host = if host.contains(':') && !host.starts_with('[') {
+            format!("[{}]", host)
+        } else {
+            host.to_string()
+        };
+        Ok(format!("{}://{}:{}", scheme, host, self.port))
+    }
+}
+
+impl ProxySettings {
+    // The host a connection to a `scheme` server is opened to first, when it
+    // is not the server itself. NO_PROXY exceptions are not considered.
+    pub fn first_hop(&self, scheme: &str) -> Option<(String, u16)> {
+        match self.mode {
+            ProxyMode::Environment => env_proxy(scheme, |name| std::env::var(name).ok()),
+            ProxyMode::Direct => None,
+            ProxyMode::Manual => Some((self.host.clone(), self.port)),
+        }
+    }
+}
+
+// Looks the variables up the way reqwest does, lowercase first.
+fn env_proxy<F>(scheme: &str, var: F) -> Option<(String, u16)>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let proxy = [format!("{}_proxy", scheme), "all_proxy".to_string()]
+        .iter()
+        .flat_map(|name| [name.clone(), name.to_uppercase()])
+        .find_map(|name| var(&name).filter(|proxy| !proxy.is_empty()))?;
+    let proxy = if proxy.contains("://") {
+        proxy
+    } else {
+        format!("http://{}", proxy)
+    };
+    let url = reqwest::Url::parse(&proxy).ok()?;
+    let host = url.host_str()?.trim_matches(['[', ']']).to_string();
+    // SOCKS has no default port known to the url crate.
+    Some((host, url.port_or_known_default().unwrap_or(1080)))
+}
+
+pub fn apply_proxy(
+    builder: ClientBuilder,
+    settings: &ProxySettings,
+) -> Result<ClientBuilder, Error> {
+    match settings.mode {
+        ProxyMode::Environment => Ok(builder),
+        ProxyMode::Direct => Ok(builder.no_proxy()),
+        ProxyMode::Manual => {
+            let proxy = Proxy::all(settings.url()?)
+                .map_err(|e| Error::InvalidInput(format!("Invalid proxy: {}", e)))?;
+            let proxy = match &settings.username {
+                Some(username) => {
+                    proxy.basic_auth(username, settings.password.as_deref().unwrap_or_default())
+                }
+                None => proxy,
+            };
+            let proxy = proxy.no_proxy(NoProxy::from_string(&settings.no_proxy.join(",")));
+            Ok(builder.proxy(proxy))
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    use super::*;
+    use crate::{
+        fake_server::{FakeServer, Fixture},
+        tauface::{
+            remote_iface::fetch_home_data,
+            settings::{
+                get_network_settings_internal, open_network_settings,
+                save_network_settings_internal, NetworkSettings,
+            },
+        },
+    };
+
+    fn manual(host: &str, port: u16) -> ProxySettings {
+        ProxySettings {
+            mode: ProxyMode::Manual,
+            host: host.to_string(),
+            port,
+            ..ProxySettings::default()
+        }
+    }
+
+    #[test]
+    fn proxy_url_uses_scheme_for_kind() {
+        let mut settings = manual("::1", 1080);
+        settings.kind = ProxyKind::Socks5;
+        assert_eq!(settings.url().unwrap(), "socks5h://[::1]:1080");
+        assert!(matches!(
+            manual("", 8080).url(),
+            Err(Error::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn environment_proxy_is_picked_per_scheme() {
+        let env = |name: &str| match name {
+            "HTTPS_PROXY" => Some("https://secure.example:8443".to_string()),
+            "all_proxy" => Some("socks5://[::1]".to_string()),
+            "http_proxy" => Some(String::new()),
+            _ => None,
+        };
+        assert_eq!(
+            env_proxy("https", env),
+            Some(("secure.example".to_string(), 8443))
+        );
+        // An empty variable does not count.
+        assert_eq!(env_proxy("http", env), Some(("::1".to_string(), 1080)));
+        assert_eq!(
+            env_proxy("http", |name| (name == "HTTP_PROXY")
+                .then(|| "proxy.example:3128".to_string())),
+            Some(("proxy.example".to_string(), 3128))
+        );
+        assert_eq!(env_proxy("http", |_| None), None);
+    }
+
+    #[tokio::test]
+    async fn manual_proxy_carries_server_requests() {
+        let fixture = Fixture::logged_in().await;
+        let proxy = FakeServer::start().await;
+        let (host, port) = proxy
+            .address()
+            .rsplit_once(':')
+            .map(|(host, port)| (host.to_string(), port.parse::<u16>().unwrap()))
+            .unwrap();
+        let settings = NetworkSettings {
+            proxy: ProxySettings {
+                username: Some("proxy-user".to_string()),
+                password: Some("proxy-secret".to_string()),
+                ..manual(&host, port)
+            },
+            ..NetworkSettings::default()
+        };
+        save_network_settings_internal(fixture.data_dir(), &settings)
+            .await
+            .unwrap();
+        fixture.client.reset().await;
+        let stored = std::fs::read_to_string(fixture.data_dir().join("network.json")).unwrap();
+        assert!(!stored.contains("proxy-secret"));
+
+        fetch_home_data(&fixture.client, fixture.data_dir())
+            .await
+            .unwrap();
+        // Both fake servers issue the same tokens, so the proxy can answer
+        // as the scheduler.
+        let request = proxy.requests_to("/state").pop().unwrap();
+        assert!(request.proxied);
+        assert_eq!(
+            request.headers["proxy-authorization"],
+            format!("Basic {}", STANDARD.encode("proxy-user:proxy-secret"))
+        );
+        assert!(fixture.server.requests_to("/state").is_empty());
+    }
+
+    #[tokio::test]
+    async fn plaintext_proxy_password_is_sealed() {
+        let fixture = Fixture::logged_out().await;
+        let data_dir = fixture.data_dir();
+        let plaintext = serde_json::json!({
+            "proxy": { "mode": "manual", "host": "proxy.example", "port": 3128,
+                       "username": "proxy-user", "password": "proxy-secret" },
+        });
+        std::fs::write(data_dir.join("network.json"), plaintext.to_string()).unwrap();
+
+        let settings = open_network_settings(data_dir).await.unwrap();
+        assert_eq!(settings.proxy.password.as_deref(), Some("proxy-secret"));
+        assert!(get_network_settings_internal(data_dir)
+            .unwrap()
+            .proxy
+            .sealed_password
+            .is_some());
+        for entry in std::fs::read_dir(data_dir).unwrap() {
+            let path = entry.unwrap().path();
+            let contents = std::fs::read_to_string(&path).unwrap_or_default();
+            assert!(!contents.contains("proxy-secret"), "{:?} kept it", path);
+        }
+    }
+}
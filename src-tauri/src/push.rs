@@ -0,0 +1,133 @@
+use std::{path::Path, time::Duration};
+
+use chrono::Local;
+use serde_json::Value;
+use tauri::{AppHandle, Emitter, Manager};
+
+use crate::{
+    cache,
+    datatypes::CurrentBlock,
+    error::Error,
+    handshake::FEATURE_EVENTS,
+    netutils::{backoff_delay, ApiClient},
+    tauface::{
+        meta::get_meta_internal,
+        profiles::active_data_dir,
+        remote_iface::{history_key, HOME_DATA_KEY},
+    },
+};
+
+const EVENTS_PATH: &str = "/events";
+// How long to wait before checking again when there is nothing to subscribe
+// to, either because nobody is logged in, the credentials are still locked or
+// the server cannot push.
+const IDLE_RECHECK: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default)]
+struct ServerEvent {
+    name: String,
+    data: String,
+}
+
+pub fn spawn(app_handle: AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        let mut attempt = 0;
+        loop {
+            let delay = match listen(&app_handle, &mut attempt).await {
+                Err(Error::Io(_) | Error::Locked | Error::Unsupported(_)) => IDLE_RECHECK,
+                result => {
+                    if let Err(e) = result {
+                        log::warn!("Event stream dropped: {}", e);
+                    }
+                    backoff_delay(attempt)
+                }
+            };
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    });
+}
+
+// Only returns once the stream is gone, resetting `attempt` as soon as the
+// connection is up so a long lived stream reconnects quickly.
+async fn listen(app_handle: &AppHandle, attempt: &mut u32) -> Result<(), Error> {
+    let data_dir = active_data_dir(app_handle)?;
+    let meta = get_meta_internal(&data_dir).await?;
+    if !meta
+        .server_info
+        .is_some_and(|server_info| server_info.supports(FEATURE_EVENTS))
+    {
+        return Err(Error::Unsupported(
+            "server does not push events".to_string(),
+        ));
+    }
+
+    let client = app_handle.state::<ApiClient>();
+    let reset = client.on_reset();
+    tokio::pin!(reset);
+    reset.as_mut().enable();
+    let mut response = client.open_stream(EVENTS_PATH, &data_dir).await?;
+    *attempt = 0;
+    log::info!("Subscribed to server events");
+
+    let mut buffer: Vec<u8> = Vec::new();
+    loop {
+        let chunk = tokio::select! {
+            chunk = response.chunk() => chunk.map_err(|e| Error::Network(e.to_string()))?,
+            _ = &mut reset => return Err(Error::Network("Connection settings changed".to_string())),
+        };
+        let Some(chunk) = chunk else {
+            break;
+        };
+        buffer.extend(chunk.iter().filter(|byte| **byte != b'\r'));
+        while let Some(end) = buffer.windows(2).position(|window| window == b"\n\n") {
+            let block: Vec<u8> = buffer.drain(..end + 2).collect();
+            // A single malformed event is not worth dropping the stream for.
+            if let Some(event) = parse_event(&String::from_utf8_lossy(&block)) {
+                let _ = dispatch(app_handle, &data_dir, event);
+            }
+        }
+    }
+    Err(Error::Network("Event stream closed".to_string()))
+}
+
+fn parse_event(block: &str) -> Option<ServerEvent> {
+    let mut event = ServerEvent::default();
+    let mut data = Vec::new();
+    for line in block.lines() {
+        let (field, value) = line.split_once(':').unwrap_or((line, ""));
+        let value = value.strip_prefix(' ').unwrap_or(value);
+        match field {
+            "event" => event.name = value.to_string(),
+            "data" => data.push(value),
+            _ => {}
+        }
+    }
+    if data.is_empty() {
+        return None;
+    }
+    event.data = data.join("\n");
+    Some(event)
+}
+
+fn dispatch(app_handle: &AppHandle, data_dir: &Path, event: ServerEvent) -> Result<(), Error> {
+    cache::mark_dirty(data_dir, HOME_DATA_KEY)?;
+    match event.name.as_str() {
+        "current_block" => {
+            let current_block: CurrentBlock =
+                serde_json::from_str(&event.data).map_err(|e| Error::Decode(e.to_string()))?;
+            app_handle
+                .emit("current-block-changed", current_block)
+                .map_err(|e| Error::Client(e.to_string()))
+        }
+        "day_data" => {
+            cache::mark_dirty(data_dir, &history_key(Local::now().date_naive()))?;
+            let day_data: Value =
+                serde_json::from_str(&event.data).map_err(|e| Error::Decode(e.to_string()))?;
+            app_handle
+                .emit("day-data-changed", day_data)
+                .map_err(|e| Error::Client(e.to_string()))
+        }
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,214 @@
+// Every persisted state file is stored as `{"schema_version": N, "data": ..}`.
+// Files from before versioning hold the bare data and count as version 0.
+// Caches are not versioned, an entry that no longer decodes is just a miss.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{endpoint::ServerEndpoint, error::Error};
+
+// Upgrades the data of a file from one version to the next.
+pub type Migration = fn(&mut Value) -> Result<(), Error>;
+
+pub struct Schema {
+    pub name: &'static str,
+    // `migrations[n]` upgrades version n to n + 1.
+    pub migrations: &'static [Migration],
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Envelope<T> {
+    pub schema_version: u32,
+    pub data: T,
+}
+
+pub const META: Schema = Schema {
+    name: "meta.json",
+    migrations: &[meta_v0_server_endpoint],
+};
+pub const PALETTE: Schema = Schema {
+    name: "palette.json",
+    migrations: &[unversioned],
+};
+pub const NETWORK: Schema = Schema {
+    name: "network.json",
+    migrations: &[unversioned],
+};
+pub const OUTBOX: Schema = Schema {
+    name: "outbox.json",
+    migrations: &[unversioned],
+};
+pub const PROFILES: Schema = Schema {
+    name: "profiles.json",
+    migrations: &[unversioned],
+};
+pub const MIRROR: Schema = Schema {
+    name: "mirror.json",
+    migrations: &[unversioned],
+};
+
+impl Schema {
+    pub fn current_version(&self) -> u32 {
+        self.migrations.len() as u32
+    }
+
+    // Returns the data at the current version and whether it had to change.
+    pub fn upgrade(&self, stored: Value) -> Result<(Value, bool), Error> {
+        let (version, mut data) =
+            match stored {
+                Value::Object(mut object)
+                    if object.contains_key("schema_version") && object.contains_key("data") =>
+                {
+                    let version = object.get("schema_version").and_then(Value::as_u64).ok_or(
+                        Error::Decode(format!("{} has an invalid schema version", self.name)),
+                    )?;
+                    (version as u32, object.remove("data").unwrap_or_default())
+                }
+                data => (0, data),
+            };
+        if version > self.current_version() {
+            return Err(Error::Decode(format!(
+                "{} was written by a newer version of the app (schema {}, this build reads up to {})",
+                self.name,
+                version,
+                self.current_version()
+            )));
+        }
+        for migration in &self.migrations[version as usize..] {
+            migration(&mut data)?;
+        }
+        Ok((data, version < self.current_version()))
+    }
+}
+
+// Versioning itself did not change the layout.
+fn unversioned(_data: &mut Value) -> Result<(), Error> {
+    Ok(())
+}
+
+// The first releases stored the server as a bare `server_ip` string.
+fn meta_v0_server_endpoint(data: &mut Value) -> Result<(), Error> {
+    let Some(meta_object) = data.as_object_mut() else {
+        return Ok(());
+    };
+    if meta_object.contains_key("server") {
+        return Ok(());
+    }
+    let Some(server_ip) = meta_object.remove("server_ip") else {
+        return Ok(());
+    };
+    let server_ip = server_ip.as_str().ok_or(Error::Decode(
+        "Stored server_ip is not a string".to_string(),
+    ))?;
+    let server = ServerEndpoint::parse(server_ip)?;
+    let server = serde_json::to_value(server).map_err(|e| Error::Client(e.to_string()))?;
+    meta_object.insert("server".to_string(), server);
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use serde::de::DeserializeOwned;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        datatypes::PaletteData,
+        tauface::{meta::Meta, outbox::PendingWrite, settings::NetworkSettings, sync::Mirror},
+    };
+
+    fn upgraded<T: DeserializeOwned>(schema: &Schema, stored: Value) -> T {
+        let (data, changed) = schema.upgrade(stored).unwrap();
+        assert!(changed);
+        serde_json::from_value(data).unwrap()
+    }
+
+    #[test]
+    fn meta_upgrades_from_every_version() {
+        // Version 0 as first released, with a bare server address.
+        let meta: Meta = upgraded(
+            &META,
+            json!({
+                "username": "tester",
+                "server_ip": "192.168.1.5:8080",
+                "access_token": "access",
+                "refresh_token": "refresh",
+            }),
+        );
+        assert_eq!(meta.server.url("/state"), "http://192.168.1.5:8080/state");
+
+        // Version 0 as last written before versioning.
+        let meta: Meta = upgraded(
+            &META,
+            json!({
+                "username": "tester",
+                "server": { "scheme": "https", "host": "example.com", "port": 443, "base_path": "" },
+                "cert_fingerprint": null,
+                "access_token": "access",
+                "refresh_token": "refresh",
+                "access_expires_at": null,
+                "server_info": { "version": "1.0.0", "api_version": 1, "features": ["sync"] },
+            }),
+        );
+        assert!(meta.server_info.unwrap().supports("sync"));
+    }
+
+    #[test]
+    fn other_files_upgrade_from_version_zero() {
+        let palette: PaletteData = upgraded(
+            &PALETTE,
+            json!({
+                "idx": 1,
+                "palette": {
+                    "name": "Violet", "accent": "#3e0e3e", "accent_hover": "#efceff",
+                    "accent2": "#de9cff", "bg": "#200a2b", "bg_dark": "#1e1e1e",
+                    "disabled_color": "#fff7c3",
+                },
+            }),
+        );
+        assert_eq!(palette.idx, 1);
+
+        // Only the timeouts existed when network settings were introduced.
+        let settings: NetworkSettings = upgraded(
+            &NETWORK,
+            json!({ "connect_timeout_secs": 5, "read_timeout_secs": 10, "max_retries": 1 }),
+        );
+        assert_eq!(settings.connect_timeout_secs, 5);
+        assert_eq!(
+            settings.poll_interval_secs,
+            NetworkSettings::default().poll_interval_secs
+        );
+
+        let pending: Vec<PendingWrite> = upgraded(&OUTBOX, json!([]));
+        assert!(pending.is_empty());
+
+        let (profiles, _) = PROFILES
+            .upgrade(json!({ "active": "default", "profiles": ["default"] }))
+            .unwrap();
+        assert_eq!(profiles["active"], "default");
+
+        let mirror: Mirror = upgraded(
+            &MIRROR,
+            json!({ "cursor": "3", "blocktypes": [], "blocks": [] }),
+        );
+        assert_eq!(mirror.cursor.as_deref(), Some("3"));
+    }
+
+    #[test]
+    fn current_files_are_left_alone_and_newer_ones_refused() {
+        let current = json!({
+            "schema_version": PALETTE.current_version(),
+            "data": { "idx": 2 },
+        });
+        let (data, changed) = PALETTE.upgrade(current).unwrap();
+        assert!(!changed);
+        assert_eq!(data["idx"], 2);
+
+        let newer = json!({
+            "schema_version": PALETTE.current_version() + 1,
+            "data": {},
+        });
+        assert!(matches!(PALETTE.upgrade(newer), Err(Error::Decode(_))));
+    }
+}
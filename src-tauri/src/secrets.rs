@@ -0,0 +1,334 @@
+use std::{path::Path, sync::Mutex};
+
+use argon2::Params;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng},
+    XChaCha20Poly1305, XNonce,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{error::Error, kdf};
+
+#[cfg(not(test))]
+const KEYRING_SERVICE: &str = "time-scheduler-client";
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+
+type Key = [u8; KEY_LEN];
+
+// Keys fetched from the secret store or unlocked by passphrase during this
+// session, by `KeySource::id`, so they are not derived on every request.
+static KEYS: Mutex<Vec<(String, Key)>> = Mutex::new(Vec::new());
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum KeySource {
+    // A random key kept in the platform secret store: the macOS Keychain, the
+    // Windows Credential Manager or the Secret Service on Linux.
+    Keyring {
+        account: String,
+    },
+    // Derived from a passphrase the user enters once per session.
+    Passphrase {
+        salt: String,
+        m_cost: u32,
+        t_cost: u32,
+        p_cost: u32,
+    },
+}
+
+impl KeySource {
+    fn id(&self) -> String {
+        match self {
+            KeySource::Keyring { account } => format!("keyring:{}", account),
+            KeySource::Passphrase { salt, .. } => format!("passphrase:{}", salt),
+        }
+    }
+}
+
+// Authenticated ciphertext together with what is needed to get its key back.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Sealed {
+    pub key: KeySource,
+    nonce: String,
+    ciphertext: String,
+}
+
+// One secret store entry per data dir, so profiles do not share keys.
+pub fn keyring_source(data_dir: &Path) -> KeySource {
+    let account = sha256::digest(data_dir.to_string_lossy().as_bytes());
+    KeySource::Keyring {
+        account: account[..16].to_string(),
+    }
+}
+
+// A second entry per data dir for settings, which have to outlive the logout
+// that destroys the key of the tokens.
+pub fn settings_keyring_source(data_dir: &Path) -> KeySource {
+    let account = sha256::digest(format!("{}:settings", data_dir.to_string_lossy()).as_bytes());
+    KeySource::Keyring {
+        account: account[..16].to_string(),
+    }
+}
+
+// Derives a key from `passphrase` under a fresh salt and keeps it for the
+// session.
+pub async fn passphrase_source(passphrase: &str) -> Result<KeySource, Error> {
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let source = KeySource::Passphrase {
+        salt: STANDARD.encode(salt),
+        m_cost: Params::DEFAULT_M_COST,
+        t_cost: Params::DEFAULT_T_COST,
+        p_cost: Params::DEFAULT_P_COST,
+    };
+    let key = derive_key(&source, passphrase).await?;
+    remember(&source, key)?;
+    Ok(source)
+}
+
+// Checks `passphrase` against `sealed` and keeps the key for the session.
+pub async fn unlock(sealed: &Sealed, passphrase: &str) -> Result<(), Error> {
+    let key = derive_key(&sealed.key, passphrase).await?;
+    open(&key, sealed).map_err(|_| Error::InvalidInput("Wrong passphrase".to_string()))?;
+    remember(&sealed.key, key)
+}
+
+// Makes sure the key for `source` is at hand, creating it in the secret store
+// if needed, so a missing store shows up before there is anything to seal.
+pub async fn prepare(source: &KeySource) -> Result<(), Error> {
+    key_for(source, true).await.map(|_| ())
+}
+
+pub fn forget(source: &KeySource) -> Result<(), Error> {
+    let id = source.id();
+    let mut keys = KEYS.lock().map_err(|e| Error::Client(e.to_string()))?;
+    keys.retain(|(known, _)| *known != id);
+    Ok(())
+}
+
+// Drops the key for good, once nothing sealed with it is left.
+pub async fn destroy(source: &KeySource) -> Result<(), Error> {
+    forget(source)?;
+    if let KeySource::Keyring { account } = source {
+        delete_keyring_key(account.clone()).await?;
+    }
+    Ok(())
+}
+
+pub async fn seal(source: &KeySource, plaintext: &[u8]) -> Result<Sealed, Error> {
+    let key = key_for(source, true).await?;
+    let cipher = XChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(&key));
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .map_err(|e| Error::Client(e.to_string()))?;
+    Ok(Sealed {
+        key: source.clone(),
+        nonce: STANDARD.encode(nonce),
+        ciphertext: STANDARD.encode(ciphertext),
+    })
+}
+
+pub async fn unseal(sealed: &Sealed) -> Result<Vec<u8>, Error> {
+    let key = key_for(&sealed.key, false).await?;
+    open(&key, sealed)
+}
+
+fn open(key: &Key, sealed: &Sealed) -> Result<Vec<u8>, Error> {
+    let nonce = STANDARD
+        .decode(&sealed.nonce)
+        .map_err(|e| Error::Decode(e.to_string()))?;
+    let ciphertext = STANDARD
+        .decode(&sealed.ciphertext)
+        .map_err(|e| Error::Decode(e.to_string()))?;
+    if nonce.len() != 24 {
+        return Err(Error::Decode(
+            "Stored nonce has the wrong length".to_string(),
+        ));
+    }
+    XChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(key))
+        .decrypt(XNonce::from_slice(&nonce), ciphertext.as_ref())
+        .map_err(|_| Error::Decode("Stored credentials failed authentication".to_string()))
+}
+
+fn remember(source: &KeySource, key: Key) -> Result<(), Error> {
+    let id = source.id();
+    let mut keys = KEYS.lock().map_err(|e| Error::Client(e.to_string()))?;
+    keys.retain(|(known, _)| *known != id);
+    keys.push((id, key));
+    Ok(())
+}
+
+async fn key_for(source: &KeySource, create: bool) -> Result<Key, Error> {
+    let id = source.id();
+    let remembered = KEYS
+        .lock()
+        .map_err(|e| Error::Client(e.to_string()))?
+        .iter()
+        .find(|(known, _)| *known == id)
+        .map(|(_, key)| *key);
+    if let Some(key) = remembered {
+        return Ok(key);
+    }
+    let key = match source {
+        KeySource::Keyring { account } => keyring_key(account.clone(), create).await?,
+        KeySource::Passphrase { .. } => return Err(Error::Locked),
+    };
+    remember(source, key)?;
+    Ok(key)
+}
+
+async fn derive_key(source: &KeySource, passphrase: &str) -> Result<Key, Error> {
+    let KeySource::Passphrase {
+        salt,
+        m_cost,
+        t_cost,
+        p_cost,
+    } = source.clone()
+    else {
+        return Err(Error::InvalidInput(
+            "Credentials are not protected by a passphrase".to_string(),
+        ));
+    };
+    let salt = STANDARD
+        .decode(salt)
+        .map_err(|e| Error::Decode(e.to_string()))?;
+    kdf::argon2id(passphrase.as_bytes().to_vec(), salt, m_cost, t_cost, p_cost).await
+}
+
+fn random_key() -> Key {
+    let mut key = [0u8; KEY_LEN];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+#[cfg(not(test))]
+async fn keyring_key(account: String, create: bool) -> Result<Key, Error> {
+    // The platform stores block, and the Secret Service one runs its own
+    // executor, so they must not be called from an async worker.
+    tokio::task::spawn_blocking(move || {
+        let entry = keyring::Entry::new(KEYRING_SERVICE, &account).map_err(keyring_error)?;
+        match entry.get_secret() {
+            Ok(secret) => Key::try_from(secret)
+                .map_err(|_| Error::Decode("Stored key has the wrong length".to_string())),
+            Err(keyring::Error::NoEntry) if create => {
+                let key = random_key();
+                entry.set_secret(&key).map_err(keyring_error)?;
+                Ok(key)
+            }
+            // Without the key the tokens cannot be recovered.
+            Err(keyring::Error::NoEntry) => Err(Error::LoginExpired),
+            Err(e) => Err(keyring_error(e)),
+        }
+    })
+    .await
+    .map_err(|e| Error::Client(e.to_string()))?
+}
+
+#[cfg(not(test))]
+async fn delete_keyring_key(account: String) -> Result<(), Error> {
+    tokio::task::spawn_blocking(move || {
+        let entry = keyring::Entry::new(KEYRING_SERVICE, &account).map_err(keyring_error)?;
+        match entry.delete_credential() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => Err(keyring_error(e)),
+        }
+    })
+    .await
+    .map_err(|e| Error::Client(e.to_string()))?
+}
+
+// Without a usable secret store the credentials need a passphrase instead.
+#[cfg(not(test))]
+fn keyring_error(e: keyring::Error) -> Error {
+    log::warn!("Secret store unavailable: {}", e);
+    Error::Locked
+}
+
+// Tests must not touch the real secret store of whoever runs them.
+#[cfg(test)]
+static TEST_KEYRING: Mutex<Vec<(String, Key)>> = Mutex::new(Vec::new());
+// Accounts for which the secret store behaves as if there was none.
+#[cfg(test)]
+static TEST_KEYRING_MISSING: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+#[cfg(test)]
+pub fn remove_test_keyring(data_dir: &Path) {
+    if let (KeySource::Keyring { account }, Ok(mut missing)) =
+        (keyring_source(data_dir), TEST_KEYRING_MISSING.lock())
+    {
+        missing.push(account);
+    }
+}
+
+#[cfg(test)]
+async fn keyring_key(account: String, create: bool) -> Result<Key, Error> {
+    let missing = TEST_KEYRING_MISSING
+        .lock()
+        .map_err(|e| Error::Client(e.to_string()))?
+        .contains(&account);
+    if missing {
+        return Err(Error::Locked);
+    }
+    let mut store = TEST_KEYRING
+        .lock()
+        .map_err(|e| Error::Client(e.to_string()))?;
+    if let Some((_, key)) = store.iter().find(|(stored, _)| *stored == account) {
+        return Ok(*key);
+    }
+    if !create {
+        return Err(Error::LoginExpired);
+    }
+    let key = random_key();
+    store.push((account, key));
+    Ok(key)
+}
+
+#[cfg(test)]
+async fn delete_keyring_key(account: String) -> Result<(), Error> {
+    let mut store = TEST_KEYRING
+        .lock()
+        .map_err(|e| Error::Client(e.to_string()))?;
+    store.retain(|(stored, _)| *stored != account);
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::fake_server::TempDir;
+
+    #[tokio::test]
+    async fn keyring_sealed_secrets_round_trip() {
+        let data_dir = TempDir::new();
+        let source = keyring_source(data_dir.path());
+        let sealed = seal(&source, b"secret").await.unwrap();
+        assert!(!sealed.ciphertext.contains("secret"));
+        assert_eq!(unseal(&sealed).await.unwrap(), b"secret");
+
+        let mut tampered = sealed.clone();
+        tampered.ciphertext = STANDARD.encode(b"not the ciphertext at all");
+        assert!(matches!(unseal(&tampered).await, Err(Error::Decode(_))));
+
+        destroy(&source).await.unwrap();
+        assert!(matches!(unseal(&sealed).await, Err(Error::LoginExpired)));
+    }
+
+    #[tokio::test]
+    async fn passphrase_sealed_secrets_stay_locked_until_unlocked() {
+        let source = passphrase_source("correct horse").await.unwrap();
+        let sealed = seal(&source, b"secret").await.unwrap();
+        forget(&source).unwrap();
+
+        assert!(matches!(unseal(&sealed).await, Err(Error::Locked)));
+        assert!(matches!(
+            unlock(&sealed, "battery staple").await,
+            Err(Error::InvalidInput(_))
+        ));
+        unlock(&sealed, "correct horse").await.unwrap();
+        assert_eq!(unseal(&sealed).await.unwrap(), b"secret");
+    }
+}
@@ -1,4 +1,11 @@
+pub mod connection;
+pub mod discovery;
+pub mod logs;
 pub mod meta;
+pub mod outbox;
 pub mod pallete;
+pub mod profiles;
 pub mod remote_iface;
+pub mod settings;
 pub mod sun;
+pub mod sync;
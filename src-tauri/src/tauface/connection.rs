@@ -0,0 +1,350 @@
+use std::{path::Path, time::Duration};
+
+use serde::Serialize;
+use tokio::net::TcpStream;
+
+use crate::{
+    endpoint::ServerEndpoint,
+    error::Error,
+    handshake::server_info_from_response,
+    netutils::{build_client, map_send_error},
+    tauface::{
+        meta::{request_login, revoke_refresh_token},
+        profiles::active_data_dir,
+        settings::get_network_settings_internal,
+    },
+};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionStep {
+    Address,
+    Reachable,
+    Tls,
+    Server,
+    Credentials,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum StepOutcome {
+    Passed,
+    Failed { error: Error },
+    // Not run, because an earlier step failed or it does not apply.
+    Skipped,
+    // Could not be checked without changing something on the server.
+    Pending { reason: String },
+}
+
+#[derive(Serialize, Debug)]
+pub struct StepResult {
+    pub step: ConnectionStep,
+    #[serde(flatten)]
+    pub outcome: StepOutcome,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct ConnectionReport {
+    pub ok: bool,
+    pub steps: Vec<StepResult>,
+}
+
+impl ConnectionReport {
+    fn passed(&mut self, step: ConnectionStep) {
+        self.steps.push(StepResult {
+            step,
+            outcome: StepOutcome::Passed,
+        });
+    }
+
+    fn failed(&mut self, step: ConnectionStep, error: Error) {
+        self.steps.push(StepResult {
+            step,
+            outcome: StepOutcome::Failed { error },
+        });
+    }
+
+    fn pending(&mut self, step: ConnectionStep, reason: String) {
+        self.steps.push(StepResult {
+            step,
+            outcome: StepOutcome::Pending { reason },
+        });
+    }
+
+    fn skipped(&mut self, step: ConnectionStep) {
+        self.steps.push(StepResult {
+            step,
+            outcome: StepOutcome::Skipped,
+        });
+    }
+
+    fn skip_rest(mut self, steps: &[ConnectionStep]) -> Self {
+        for step in steps {
+            self.skipped(*step);
+        }
+        self
+    }
+}
+
+// Runs the same checks as `save_meta` one at a time, so the user can see
+// which one fails. Nothing is written to the data dir.
+#[tauri::command]
+pub async fn test_connection(
+    username: &str,
+    password: &str,
+    server_ip: &str,
+    cert_fingerprint: Option<String>,
+    app_handle: tauri::AppHandle,
+) -> Result<ConnectionReport, Error> {
+    let data_dir = active_data_dir(&app_handle)?;
+    test_connection_internal(&data_dir, username, password, server_ip, cert_fingerprint).await
+}
+
+pub async fn test_connection_internal(
+    data_dir: &Path,
+    username: &str,
+    password: &str,
+    server_ip: &str,
+    cert_fingerprint: Option<String>,
+) -> Result<ConnectionReport, Error> {
+    use ConnectionStep::*;
+
+    let mut report = ConnectionReport::default();
+    let server = match ServerEndpoint::parse(server_ip) {
+        Ok(server) => server,
+        Err(e) => {
+            report.failed(Address, e);
+            return Ok(report.skip_rest(&[Reachable, Tls, Server, Credentials]));
+        }
+    };
+    report.passed(Address);
+
+    let settings = get_network_settings_internal(data_dir)?;
+    let connect_timeout = Duration::from_secs(settings.connect_timeout_secs);
+    // Through a proxy only the proxy has to be reachable from here.
+    let (host, port) = settings
+        .proxy
+        .first_hop(&server.scheme)
+        .unwrap_or((server.host.clone(), server.port));
+    if let Err(e) = connect(&host, port, connect_timeout).await {
+        report.failed(Reachable, e);
+        return Ok(report.skip_rest(&[Tls, Server, Credentials]));
+    }
+    report.passed(Reachable);
+
+    let http_client = build_client(data_dir, cert_fingerprint).await?;
+    let is_tls = server.scheme == "https";
+    let response = match http_client.get(server.url("/version")).send().await {
+        Ok(response) => response,
+        Err(e) => match map_send_error(e) {
+            Error::Tls(message) => {
+                report.failed(Tls, Error::Tls(message));
+                return Ok(report.skip_rest(&[Server, Credentials]));
+            }
+            e => {
+                report.skipped(Tls);
+                report.failed(Server, e);
+                return Ok(report.skip_rest(&[Credentials]));
+            }
+        },
+    };
+    if is_tls {
+        report.passed(Tls);
+    } else {
+        report.skipped(Tls);
+    }
+
+    let server_info = match server_info_from_response(response).await {
+        Ok(server_info) => {
+            report.passed(Server);
+            server_info
+        }
+        Err(Error::Incompatible(message)) => {
+            report.failed(Server, Error::Incompatible(message));
+            return Ok(report.skip_rest(&[Credentials]));
+        }
+        Err(e) => {
+            report.failed(
+                Server,
+                Error::Incompatible(format!(
+                    "{} does not look like a time-scheduler server: {}",
+                    server.url(""),
+                    e
+                )),
+            );
+            return Ok(report.skip_rest(&[Credentials]));
+        }
+    };
+
+    // The test never sends the legacy key over plain http, which needs the
+    // confirmation the real login asks for, and never registers a verifier.
+    match request_login(&http_client, &server, &server_info, password, false, true).await {
+        Ok(None) => report.pending(
+            Credentials,
+            "The account moves to challenge login on the next login".to_string(),
+        ),
+        Ok(Some(tokens)) => {
+            // The test must not leave a session behind on the server.
+            if let Err(e) = revoke_refresh_token(&http_client, &server, &tokens.refresh_token).await
+            {
+                log::warn!("Could not revoke the tokens of the connection test: {}", e);
+            }
+            log::info!(
+                "Connection test passed for {} on {}",
+                username,
+                server.url("/")
+            );
+            report.passed(Credentials);
+            report.ok = true;
+        }
+        Err(e) => report.failed(Credentials, e),
+    }
+    Ok(report)
+}
+
+async fn connect(host: &str, port: u16, timeout: Duration) -> Result<(), Error> {
+    match tokio::time::timeout(timeout, TcpStream::connect((host, port))).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(Error::Network(format!(
+            "Cannot connect to {}:{}: {}",
+            host, port, e
+        ))),
+        Err(_) => Err(Error::Timeout(format!(
+            "Connecting to {}:{} timed out",
+            host, port
+        ))),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::fake_server::{Fixture, PASSWORD, USERNAME};
+
+    fn outcomes(report: &ConnectionReport) -> Vec<(ConnectionStep, &'static str)> {
+        report
+            .steps
+            .iter()
+            .map(|step| {
+                let status = match step.outcome {
+                    StepOutcome::Passed => "passed",
+                    StepOutcome::Failed { .. } => "failed",
+                    StepOutcome::Skipped => "skipped",
+                    StepOutcome::Pending { .. } => "pending",
+                };
+                (step.step, status)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn valid_credentials_pass_every_step_without_saving() {
+        let fixture = Fixture::logged_out().await;
+        let report = test_connection_internal(
+            fixture.data_dir(),
+            USERNAME,
+            PASSWORD,
+            &fixture.server.address(),
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(report.ok);
+        assert_eq!(
+            outcomes(&report),
+            [
+                (ConnectionStep::Address, "passed"),
+                (ConnectionStep::Reachable, "passed"),
+                (ConnectionStep::Tls, "skipped"),
+                (ConnectionStep::Server, "passed"),
+                (ConnectionStep::Credentials, "passed"),
+            ]
+        );
+        assert!(!fixture.data_dir().join("meta.json").exists());
+        assert_eq!(fixture.server.revoked_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn each_failure_is_reported_at_its_step() {
+        let fixture = Fixture::logged_out().await;
+        let address = fixture.server.address();
+
+        let report =
+            test_connection_internal(fixture.data_dir(), USERNAME, "wrong", &address, None)
+                .await
+                .unwrap();
+        assert!(!report.ok);
+        assert!(matches!(
+            report.steps[4].outcome,
+            StepOutcome::Failed {
+                error: Error::Unauthorized
+            }
+        ));
+
+        fixture
+            .server
+            .set_server_info(Some(json!({ "unrelated": true })));
+        let report =
+            test_connection_internal(fixture.data_dir(), USERNAME, PASSWORD, &address, None)
+                .await
+                .unwrap();
+        assert_eq!(
+            outcomes(&report)[3..],
+            [
+                (ConnectionStep::Server, "failed"),
+                (ConnectionStep::Credentials, "skipped"),
+            ]
+        );
+
+        let report =
+            test_connection_internal(fixture.data_dir(), USERNAME, PASSWORD, "ftp://x", None)
+                .await
+                .unwrap();
+        assert_eq!(outcomes(&report)[0], (ConnectionStep::Address, "failed"));
+        assert_eq!(report.steps.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn unregistered_account_is_left_alone() {
+        let fixture = Fixture::logged_out().await;
+        fixture.server.forget_verifier();
+        let report = test_connection_internal(
+            fixture.data_dir(),
+            USERNAME,
+            PASSWORD,
+            &fixture.server.address(),
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(!report.ok);
+        assert_eq!(
+            outcomes(&report)[4],
+            (ConnectionStep::Credentials, "pending")
+        );
+        assert!(!fixture.server.has_verifier());
+        assert!(fixture.server.requests_to("/auth/login").is_empty());
+    }
+
+    #[tokio::test]
+    async fn closed_port_fails_reachability() {
+        let fixture = Fixture::logged_out().await;
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let report =
+            test_connection_internal(fixture.data_dir(), USERNAME, PASSWORD, &address, None)
+                .await
+                .unwrap();
+        assert_eq!(
+            outcomes(&report)[1..3],
+            [
+                (ConnectionStep::Reachable, "failed"),
+                (ConnectionStep::Tls, "skipped"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    collections::HashSet,
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::{net::UdpSocket, time::Instant};
+
+use crate::error::Error;
+
+// Servers listen for the probe on this port and answer to the sender.
+pub const DISCOVERY_PORT: u16 = 41234;
+const DISCOVERY_PROBE: &str = "time-scheduler-discover";
+const DEFAULT_TIMEOUT_MS: u64 = 1500;
+
+#[derive(Deserialize, Debug)]
+struct DiscoveryReply {
+    name: String,
+    version: String,
+    port: u16,
+    #[serde(default)]
+    tls: bool,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DiscoveredServer {
+    pub host: String,
+    pub port: u16,
+    pub name: String,
+    pub version: String,
+    // Ready to be passed to `save_meta` as the server address.
+    pub address: String,
+}
+
+#[tauri::command]
+pub async fn discover_servers(timeout_ms: Option<u64>) -> Result<Vec<DiscoveredServer>, Error> {
+    discover(
+        SocketAddr::from((Ipv4Addr::BROADCAST, DISCOVERY_PORT)),
+        Duration::from_millis(timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS)),
+    )
+    .await
+}
+
+pub async fn discover(
+    target: SocketAddr,
+    timeout: Duration,
+) -> Result<Vec<DiscoveredServer>, Error> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .await
+        .map_err(|e| Error::Network(e.to_string()))?;
+    socket
+        .set_broadcast(true)
+        .map_err(|e| Error::Network(e.to_string()))?;
+    let probe = json!({ "probe": DISCOVERY_PROBE }).to_string();
+    socket
+        .send_to(probe.as_bytes(), target)
+        .await
+        .map_err(|e| Error::Network(e.to_string()))?;
+
+    let deadline = Instant::now() + timeout;
+    let mut seen = HashSet::new();
+    let mut servers = Vec::new();
+    let mut buffer = [0u8; 2048];
+    loop {
+        let received = tokio::time::timeout_at(deadline, socket.recv_from(&mut buffer)).await;
+        let Ok(received) = received else {
+            break;
+        };
+        let (len, from) = received.map_err(|e| Error::Network(e.to_string()))?;
+        // Anything else answering on the port is not a scheduler server.
+        let Ok(reply) = serde_json::from_slice::<DiscoveryReply>(&buffer[..len]) else {
+            continue;
+        };
+        if !seen.insert((from.ip(), reply.port)) {
+            continue;
+        }
+        servers.push(discovered_server(from, reply));
+    }
+    Ok(servers)
+}
+
+fn discovered_server(from: SocketAddr, reply: DiscoveryReply) -> DiscoveredServer {
+    let host = from.ip().to_string();
+    let authority = match from {
+        SocketAddr::V4(_) => format!("{}:{}", host, reply.port),
+        SocketAddr::V6(_) => format!("[{}]:{}", host, reply.port),
+    };
+    let address = if reply.tls {
+        format!("https://{}", authority)
+    } else {
+        authority
+    };
+    DiscoveredServer {
+        host,
+        port: reply.port,
+        name: reply.name,
+        version: reply.version,
+        address,
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn replies_are_collected_once_per_server() {
+        let responder = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = responder.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 512];
+            let (len, from) = responder.recv_from(&mut buffer).await.unwrap();
+            let probe: serde_json::Value = serde_json::from_slice(&buffer[..len]).unwrap();
+            assert_eq!(probe["probe"], DISCOVERY_PROBE);
+            let reply = json!({ "name": "home", "version": "1.0.0", "port": 8080, "tls": true });
+            for _ in 0..2 {
+                responder
+                    .send_to(reply.to_string().as_bytes(), from)
+                    .await
+                    .unwrap();
+            }
+            responder.send_to(b"not json", from).await.unwrap();
+        });
+
+        let servers = discover(target, Duration::from_millis(300)).await.unwrap();
+        assert_eq!(
+            servers,
+            [DiscoveredServer {
+                host: "127.0.0.1".to_string(),
+                port: 8080,
+                name: "home".to_string(),
+                version: "1.0.0".to_string(),
+                address: "https://127.0.0.1:8080".to_string(),
+            }]
+        );
+    }
+}
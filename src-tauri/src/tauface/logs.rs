@@ -0,0 +1,20 @@
+use tauri::Manager;
+
+use crate::{
+    error::Error,
+    logging::{recent_logs, LogLine},
+};
+
+const DEFAULT_LOG_LINES: usize = 200;
+
+#[tauri::command]
+pub async fn get_recent_logs(
+    limit: Option<usize>,
+    app_handle: tauri::AppHandle,
+) -> Result<Vec<LogLine>, Error> {
+    let data_dir = app_handle
+        .path()
+        .app_local_data_dir()
+        .map_err(|e| Error::Io(e.to_string()))?;
+    recent_logs(&data_dir, limit.unwrap_or(DEFAULT_LOG_LINES))
+}
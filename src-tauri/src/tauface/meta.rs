@@ -1,18 +1,71 @@
-use std::path::Path;
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
 
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, TimeDelta, Utc};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use sha256::digest;
-use tauri::Manager;
+use tauri::{Emitter, State};
 
-use crate::error::Error;
+use crate::{
+    challenge::{self, Challenge, ChallengeResponse, Registration},
+    endpoint::ServerEndpoint,
+    error::Error,
+    handshake::{fetch_server_info, ServerInfo, FEATURE_CHALLENGE_LOGIN},
+    netutils::{build_client, ensure_success, map_send_error, ApiClient},
+    persist, schema,
+    secrets::{self, KeySource, Sealed},
+    tauface::profiles::active_data_dir,
+    tls::{parse_certificates, SERVER_CA_FILE},
+};
+
+// The tokens are kept encrypted in meta.json under this field.
+const SEALED_FIELD: &str = "sealed";
+const TOKEN_FIELDS: [&str; 2] = ["access_token", "refresh_token"];
+// Everything tied to the signed in account. Network settings and the pinned
+// certificate belong to the server and survive a logout.
+const ACCOUNT_ENTRIES: [&str; 4] = ["meta.json", "outbox.json", "mirror.json", "cache"];
+
+// Data dirs whose plaintext meta.json could not be sealed for lack of a secret
+// store, so it is not tried again on every request.
+static PLAINTEXT_KEPT: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Meta {
     pub username: String,
-    pub server_ip: String,
+    pub server: ServerEndpoint,
+    #[serde(default)]
+    pub cert_fingerprint: Option<String>,
     pub access_token: String,
     pub refresh_token: String,
+    #[serde(default)]
+    pub access_expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub server_info: Option<ServerInfo>,
+}
+
+// What the webview gets to see of the stored account, the tokens never leave
+// the Rust side.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MetaSummary {
+    pub username: String,
+    pub server: ServerEndpoint,
+    #[serde(default)]
+    pub server_info: Option<ServerInfo>,
+}
+
+impl From<Meta> for MetaSummary {
+    fn from(meta: Meta) -> Self {
+        MetaSummary {
+            username: meta.username,
+            server: meta.server,
+            server_info: meta.server_info,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -20,68 +73,826 @@ pub struct LoginRequest {
     pub key: String,
 }
 
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+enum LoginBody {
+    // Replayable, only sent to servers that never offered a challenge.
+    Legacy(LoginRequest),
+    Challenge(ChallengeResponse),
+    // Sends the legacy key a last time to replace it with a verifier.
+    Register(Registration),
+}
+
 #[derive(Deserialize, Debug)]
 pub struct LoginResponse {
     pub access_token: String,
     pub refresh_token: String,
+    #[serde(default)]
+    pub expires_in: Option<i64>,
 }
 
+impl LoginResponse {
+    // Prefers the server's `expires_in` and falls back to the JWT `exp` claim.
+    pub fn access_expires_at(&self) -> Option<DateTime<Utc>> {
+        if let Some(expires_in) = self.expires_in {
+            return Some(Utc::now() + TimeDelta::seconds(expires_in));
+        }
+        let payload = self.access_token.split('.').nth(1)?;
+        let payload = URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')).ok()?;
+        let claims: Value = serde_json::from_slice(&payload).ok()?;
+        DateTime::from_timestamp(claims.get("exp")?.as_i64()?, 0)
+    }
+}
+
+impl Meta {
+    pub fn access_token_expiring(&self, margin: TimeDelta) -> bool {
+        self.access_expires_at
+            .is_some_and(|expires_at| expires_at - margin <= Utc::now())
+    }
+}
+
+// The tokens are sealed with `passphrase` when one is given, otherwise with
+// the platform secret store. `allow_insecure_login` is the user's consent to
+// send the legacy password key over plain http.
 #[tauri::command]
+#[allow(clippy::too_many_arguments)]
 pub async fn save_meta(
     username: &str,
     password: &str,
     server_ip: &str,
+    cert_fingerprint: Option<String>,
+    passphrase: Option<String>,
+    allow_insecure_login: Option<bool>,
     app_handle: tauri::AppHandle,
+    client: State<'_, ApiClient>,
 ) -> Result<(), Error> {
-    let data_dir = app_handle
-        .path()
-        .app_local_data_dir()
-        .map_err(|e| Error::Client(e.to_string()))?;
+    let data_dir = active_data_dir(&app_handle)?;
+    login_internal(
+        &data_dir,
+        username,
+        password,
+        server_ip,
+        cert_fingerprint,
+        passphrase.as_deref(),
+        allow_insecure_login.unwrap_or(false),
+    )
+    .await?;
+    client.reset().await;
+    Ok(())
+}
+
+pub async fn login_internal(
+    data_dir: &Path,
+    username: &str,
+    password: &str,
+    server_ip: &str,
+    cert_fingerprint: Option<String>,
+    passphrase: Option<&str>,
+    allow_insecure_login: bool,
+) -> Result<Meta, Error> {
     if !data_dir.exists() {
-        std::fs::create_dir_all(&data_dir).map_err(|e| Error::Client(e.to_string()))?;
+        std::fs::create_dir_all(data_dir).map_err(|e| Error::Io(e.to_string()))?;
     }
-    let meta_path = data_dir.join("meta.json");
+    let server = ServerEndpoint::parse(server_ip)?;
+    // Settled before logging in, so a missing secret store is reported
+    // without leaving a session behind on the server.
+    let source = login_key_source(data_dir, passphrase).await?;
+
+    let http_client = build_client(data_dir, cert_fingerprint.clone()).await?;
+    let server_info = fetch_server_info(&http_client, &server).await?;
+    ensure_no_downgrade(data_dir, &server, &server_info).await?;
+    let response = request_login(
+        &http_client,
+        &server,
+        &server_info,
+        password,
+        allow_insecure_login,
+        false,
+    )
+    .await?
+    .ok_or_else(|| Error::Client("login stopped before registering".to_string()))?;
+    log::info!("Logged in as {} on {}", username, server.url("/"));
+
+    let meta = Meta {
+        username: username.to_string(),
+        server,
+        cert_fingerprint,
+        access_expires_at: response.access_expires_at(),
+        access_token: response.access_token,
+        refresh_token: response.refresh_token,
+        server_info: Some(server_info),
+    };
+
+    write_meta(data_dir, &meta, &source).await?;
+    Ok(meta)
+}
 
-    let hashed_pass = digest(password);
+async fn login_key_source(data_dir: &Path, passphrase: Option<&str>) -> Result<KeySource, Error> {
+    if let Some(passphrase) = passphrase.filter(|passphrase| !passphrase.is_empty()) {
+        return secrets::passphrase_source(passphrase).await;
+    }
+    let source = match read_sealed(data_dir) {
+        Some(sealed) => sealed.key,
+        None => secrets::keyring_source(data_dir),
+    };
+    secrets::prepare(&source).await?;
+    Ok(source)
+}
 
-    let login_req = LoginRequest { key: hashed_pass };
-    let client = Client::new();
+// Once a server has offered challenge login, falling back to the password
+// key would let anyone in the middle strip the feature and capture the key.
+async fn ensure_no_downgrade(
+    data_dir: &Path,
+    server: &ServerEndpoint,
+    server_info: &ServerInfo,
+) -> Result<(), Error> {
+    let Ok(previous) = get_meta_internal(data_dir).await else {
+        return Ok(());
+    };
+    let offered_before = previous.server == *server
+        && previous
+            .server_info
+            .is_some_and(|previous| previous.supports(FEATURE_CHALLENGE_LOGIN));
+    if offered_before && !server_info.supports(FEATURE_CHALLENGE_LOGIN) {
+        return Err(Error::Incompatible(format!(
+            "{} no longer offers challenge login, refusing to send the password",
+            server.url("")
+        )));
+    }
+    Ok(())
+}
+
+// Accounts that only have the legacy password key register a verifier on
+// their next login, after which the server refuses the key. Over plain http
+// the key is only sent once the user has allowed it. A dry run leaves such an
+// account alone and returns None.
+pub async fn request_login(
+    client: &Client,
+    server: &ServerEndpoint,
+    server_info: &ServerInfo,
+    password: &str,
+    allow_insecure_login: bool,
+    dry_run: bool,
+) -> Result<Option<LoginResponse>, Error> {
+    let login_body = if server_info.supports(FEATURE_CHALLENGE_LOGIN) {
+        let response = client
+            .post(server.url("/auth/challenge"))
+            .send()
+            .await
+            .map_err(map_send_error)?;
+        let challenge = ensure_success(response)
+            .await?
+            .json::<Challenge>()
+            .await
+            .map_err(|e| Error::Decode(e.to_string()))?;
+        if challenge.registered {
+            LoginBody::Challenge(challenge::respond(challenge, password).await?)
+        } else if dry_run {
+            return Ok(None);
+        } else {
+            ensure_key_may_be_sent(server, allow_insecure_login)?;
+            log::info!("Registering a login verifier on {}", server.url(""));
+            LoginBody::Register(challenge::register(challenge, password).await?)
+        }
+    } else {
+        ensure_key_may_be_sent(server, allow_insecure_login)?;
+        log::warn!(
+            "{} only supports the legacy password key login",
+            server.url("")
+        );
+        LoginBody::Legacy(LoginRequest {
+            key: digest(password),
+        })
+    };
     let response = client
-        .post(format!("http://{}/auth/login", server_ip))
-        .json(&login_req)
+        .post(server.url("/auth/login"))
+        .json(&login_body)
         .send()
         .await
-        .map_err(|e| Error::Server(e.to_string()))?
+        .map_err(map_send_error)?;
+    ensure_success(response)
+        .await?
         .json::<LoginResponse>()
         .await
+        .map(Some)
+        .map_err(|e| Error::Decode(e.to_string()))
+}
+
+// The legacy key is as good as the password to anyone who can read it.
+fn ensure_key_may_be_sent(
+    server: &ServerEndpoint,
+    allow_insecure_login: bool,
+) -> Result<(), Error> {
+    if server.scheme == "https" || allow_insecure_login {
+        return Ok(());
+    }
+    Err(Error::InsecureLogin(format!(
+        "{} needs the password key sent over an unencrypted connection",
+        server.url("")
+    )))
+}
+
+#[tauri::command]
+pub async fn server_handshake(app_handle: tauri::AppHandle) -> Result<ServerInfo, Error> {
+    let data_dir = active_data_dir(&app_handle)?;
+    let mut meta = get_meta_internal(&data_dir).await?;
+    let http_client = build_client(&data_dir, meta.cert_fingerprint.clone()).await?;
+    let server_info = fetch_server_info(&http_client, &meta.server).await?;
+    meta.server_info = Some(server_info.clone());
+    save_meta_internal(&data_dir, &meta).await?;
+    Ok(server_info)
+}
+
+// Returns whether the server revoked the refresh token. The local data is
+// wiped either way.
+#[tauri::command]
+pub async fn logout(
+    app_handle: tauri::AppHandle,
+    client: State<'_, ApiClient>,
+) -> Result<bool, Error> {
+    let data_dir = active_data_dir(&app_handle)?;
+    let revoked = logout_internal(&client, &data_dir).await?;
+    client.reset().await;
+    app_handle
+        .emit("logged-out", ())
         .map_err(|e| Error::Client(e.to_string()))?;
-    println!("{:?}", response);
+    Ok(revoked)
+}
 
-    let meta = Meta {
-        username: username.to_string(),
-        server_ip: server_ip.to_string(),
-        access_token: response.access_token,
-        refresh_token: response.refresh_token,
+// Revocation is best effort, an unreachable or older server must not keep the
+// user signed in on this device.
+pub async fn logout_internal(client: &ApiClient, data_dir: &Path) -> Result<bool, Error> {
+    let revoked = match get_meta_internal(data_dir).await {
+        Ok(meta) => match revoke_meta_tokens(data_dir, &meta).await {
+            Ok(()) => true,
+            Err(e) => {
+                log::warn!("Could not revoke the refresh token: {}", e);
+                false
+            }
+        },
+        Err(e) => {
+            log::warn!("Logging out without stored credentials: {}", e);
+            false
+        }
     };
 
-    let meta_json = serde_json::to_string(&meta).map_err(|e| Error::Client(e.to_string()))?;
-    std::fs::write(meta_path, meta_json).map_err(|e| Error::Client(e.to_string()))?;
+    // Queued writes belong to this account and must not be replayed for the
+    // next one.
+    let _outbox = client.lock_outbox().await;
+    let sealed = read_sealed(data_dir);
+    for entry in ACCOUNT_ENTRIES {
+        let path = data_dir.join(entry);
+        if path.is_dir() {
+            std::fs::remove_dir_all(&path).map_err(|e| Error::Io(e.to_string()))?;
+        } else {
+            persist::remove(&path)?;
+        }
+    }
+    if let Some(sealed) = sealed {
+        secrets::destroy(&sealed.key).await?;
+    }
+    log::info!("Logged out");
+    Ok(revoked)
+}
+
+async fn revoke_meta_tokens(data_dir: &Path, meta: &Meta) -> Result<(), Error> {
+    let http_client = build_client(data_dir, meta.cert_fingerprint.clone()).await?;
+    revoke_refresh_token(&http_client, &meta.server, &meta.refresh_token).await
+}
+
+// The refresh token authenticates itself, so this works after the access
+// token has expired, which is the usual case after being idle.
+pub async fn revoke_refresh_token(
+    client: &Client,
+    server: &ServerEndpoint,
+    refresh_token: &str,
+) -> Result<(), Error> {
+    let response = client
+        .post(server.url("/auth/logout"))
+        .body(refresh_token.to_string())
+        .send()
+        .await
+        .map_err(map_send_error)?;
+    ensure_success(response).await?;
     Ok(())
 }
 
 #[tauri::command]
-pub async fn get_meta(app_handle: tauri::AppHandle) -> Result<Meta, Error> {
-    let data_dir = app_handle
-        .path()
-        .app_local_data_dir()
-        .map_err(|e| Error::Client(e.to_string()))?;
-    get_meta_internal(&data_dir).await
+pub async fn save_server_cert(
+    pem: Option<String>,
+    app_handle: tauri::AppHandle,
+    client: State<'_, ApiClient>,
+) -> Result<(), Error> {
+    let data_dir = active_data_dir(&app_handle)?;
+    if !data_dir.exists() {
+        std::fs::create_dir_all(&data_dir).map_err(|e| Error::Io(e.to_string()))?;
+    }
+    let cert_path = data_dir.join(SERVER_CA_FILE);
+    match pem {
+        Some(pem) => {
+            parse_certificates(pem.as_bytes())?;
+            // A torn file would break every client built from it.
+            persist::write(&cert_path, pem.as_bytes())?;
+        }
+        None => persist::remove(&cert_path)?,
+    }
+    client.reset().await;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn unlock_credentials(
+    passphrase: &str,
+    app_handle: tauri::AppHandle,
+) -> Result<(), Error> {
+    let data_dir = active_data_dir(&app_handle)?;
+    unlock_credentials_internal(&data_dir, passphrase).await
+}
+
+pub async fn unlock_credentials_internal(data_dir: &Path, passphrase: &str) -> Result<(), Error> {
+    let sealed = read_sealed(data_dir).ok_or(Error::InvalidInput(
+        "There are no stored credentials to unlock".to_string(),
+    ))?;
+    secrets::unlock(&sealed, passphrase).await
+}
+
+// Protects the tokens with `passphrase`, or with the platform secret store
+// when it is None.
+#[tauri::command]
+pub async fn set_credentials_passphrase(
+    passphrase: Option<String>,
+    app_handle: tauri::AppHandle,
+) -> Result<(), Error> {
+    let data_dir = active_data_dir(&app_handle)?;
+    set_credentials_passphrase_internal(&data_dir, passphrase.as_deref()).await
+}
+
+pub async fn set_credentials_passphrase_internal(
+    data_dir: &Path,
+    passphrase: Option<&str>,
+) -> Result<(), Error> {
+    let meta = get_meta_internal(data_dir).await?;
+    let source = match passphrase {
+        Some(passphrase) if !passphrase.is_empty() => {
+            secrets::passphrase_source(passphrase).await?
+        }
+        _ => secrets::keyring_source(data_dir),
+    };
+    let previous = read_sealed(data_dir).map(|sealed| sealed.key);
+    write_meta(data_dir, &meta, &source).await?;
+    // Nothing is sealed with the previous key any more, and the backup that
+    // still is could not be opened once it is gone.
+    if let Some(previous) = previous.filter(|previous| *previous != source) {
+        persist::discard_backup(&data_dir.join("meta.json"))?;
+        secrets::destroy(&previous).await?;
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn get_meta(app_handle: tauri::AppHandle) -> Result<MetaSummary, Error> {
+    let data_dir = active_data_dir(&app_handle)?;
+    Ok(get_meta_internal(&data_dir).await?.into())
 }
 
 pub async fn get_meta_internal(data_dir: &Path) -> Result<Meta, Error> {
-    let meta_path = data_dir.join("meta.json");
-    let meta_json =
-        std::fs::read_to_string(&meta_path).map_err(|e| Error::Client(e.to_string()))?;
-    let meta: Meta = serde_json::from_str(&meta_json).map_err(|e| Error::Client(e.to_string()))?;
+    let mut meta_value: Value =
+        persist::read_versioned(&data_dir.join("meta.json"), &schema::META)?;
+    let sealed = unseal_tokens(&mut meta_value).await?;
+    let meta: Meta =
+        serde_json::from_value(meta_value).map_err(|e| Error::Decode(e.to_string()))?;
+    if !sealed && !plaintext_kept(data_dir)? {
+        save_meta_internal(data_dir, &meta).await?;
+    }
     Ok(meta)
 }
+
+// Puts the decrypted tokens back into `meta_value`. Returns false for files
+// written before encryption, which hold the tokens in plaintext.
+async fn unseal_tokens(meta_value: &mut Value) -> Result<bool, Error> {
+    let Some(meta_object) = meta_value.as_object_mut() else {
+        return Ok(false);
+    };
+    let Some(sealed) = meta_object.remove(SEALED_FIELD) else {
+        return Ok(false);
+    };
+    let sealed: Sealed =
+        serde_json::from_value(sealed).map_err(|e| Error::Decode(e.to_string()))?;
+    let tokens = secrets::unseal(&sealed).await?;
+    let tokens: serde_json::Map<String, Value> =
+        serde_json::from_slice(&tokens).map_err(|e| Error::Decode(e.to_string()))?;
+    meta_object.extend(tokens);
+    Ok(true)
+}
+
+// Reads only what meta.json keeps in the clear, so neither the secret store
+// nor a migration is touched.
+pub fn read_meta_summary(data_dir: &Path) -> Result<MetaSummary, Error> {
+    persist::peek_versioned(&data_dir.join("meta.json"), &schema::META)
+}
+
+// The key the tokens in `data_dir` are sealed with, if there are any.
+pub fn sealed_key(data_dir: &Path) -> Option<KeySource> {
+    read_sealed(data_dir).map(|sealed| sealed.key)
+}
+
+fn read_sealed(data_dir: &Path) -> Option<Sealed> {
+    let mut meta_value: Value =
+        persist::read_versioned(&data_dir.join("meta.json"), &schema::META).ok()?;
+    serde_json::from_value(meta_value.get_mut(SEALED_FIELD)?.take()).ok()
+}
+
+// Seals the tokens with the key the file already uses. A file written before
+// encryption moves to a secret store key, or without a secret store stays in
+// plaintext until a passphrase is set.
+pub async fn save_meta_internal(data_dir: &Path, meta: &Meta) -> Result<(), Error> {
+    if let Some(sealed) = read_sealed(data_dir) {
+        return write_meta(data_dir, meta, &sealed.key).await;
+    }
+    if !plaintext_kept(data_dir)? {
+        match write_meta(data_dir, meta, &secrets::keyring_source(data_dir)).await {
+            Err(Error::Locked) => {
+                log::warn!("No secret store available, credentials stay unencrypted");
+                PLAINTEXT_KEPT
+                    .lock()
+                    .map_err(|e| Error::Client(e.to_string()))?
+                    .push(data_dir.to_path_buf());
+            }
+            result => return result,
+        }
+    }
+    persist::write_versioned(&data_dir.join("meta.json"), &schema::META, meta)
+}
+
+fn plaintext_kept(data_dir: &Path) -> Result<bool, Error> {
+    let kept = PLAINTEXT_KEPT
+        .lock()
+        .map_err(|e| Error::Client(e.to_string()))?;
+    Ok(kept.iter().any(|kept| kept == data_dir))
+}
+
+async fn write_meta(data_dir: &Path, meta: &Meta, source: &KeySource) -> Result<(), Error> {
+    let meta_path = data_dir.join("meta.json");
+    let was_plaintext = meta_path.exists() && read_sealed(data_dir).is_none();
+    let mut meta_value = serde_json::to_value(meta).map_err(|e| Error::Client(e.to_string()))?;
+    if let Some(meta_object) = meta_value.as_object_mut() {
+        let tokens: serde_json::Map<String, Value> = TOKEN_FIELDS
+            .iter()
+            .filter_map(|field| meta_object.remove_entry(*field))
+            .collect();
+        let tokens = serde_json::to_vec(&tokens).map_err(|e| Error::Client(e.to_string()))?;
+        let sealed = secrets::seal(source, &tokens).await?;
+        let sealed = serde_json::to_value(sealed).map_err(|e| Error::Client(e.to_string()))?;
+        meta_object.insert(SEALED_FIELD.to_string(), sealed);
+    }
+    persist::write_versioned(&meta_path, &schema::META, &meta_value)?;
+    // The backup of a file from before encryption still holds the tokens.
+    if was_plaintext {
+        persist::discard_backup(&meta_path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::fake_server::{Fixture, Scripted, PASSWORD, USERNAME};
+
+    async fn login_allowing_insecure(fixture: &Fixture) -> Result<Meta, Error> {
+        login_internal(
+            fixture.data_dir(),
+            USERNAME,
+            PASSWORD,
+            &fixture.server.address(),
+            None,
+            None,
+            true,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn login_stores_tokens_and_server_info() {
+        let fixture = Fixture::logged_in().await;
+        let meta = get_meta_internal(fixture.data_dir()).await.unwrap();
+        assert_eq!(meta.access_token, "access-1");
+        assert_eq!(meta.refresh_token, "refresh-1");
+        assert!(meta.access_expires_at.is_some());
+        let server_info = meta.server_info.unwrap();
+        assert!(server_info.supports("split"));
+
+        let login = fixture.server.requests_to("/auth/login").pop().unwrap();
+        assert!(!login.body.contains(&digest(PASSWORD)));
+    }
+
+    #[tokio::test]
+    async fn challenge_proof_cannot_be_replayed() {
+        let fixture = Fixture::logged_in().await;
+        let login = fixture.server.requests_to("/auth/login").pop().unwrap();
+        assert!(login.body.contains("proof"));
+
+        let replay = Client::new()
+            .post(format!("http://{}/auth/login", fixture.server.address()))
+            .header("content-type", "application/json")
+            .body(login.body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(replay.status(), 401);
+    }
+
+    #[tokio::test]
+    async fn weak_challenge_is_refused_before_sending_a_proof() {
+        let fixture = Fixture::logged_out().await;
+        fixture.server.script(
+            "/auth/challenge",
+            Scripted::Respond {
+                status: 200,
+                body: json!({
+                    "nonce": "weak",
+                    "salt": "ZmFrZS1zZXJ2ZXItc2FsdA==",
+                    "m_cost": 8,
+                    "t_cost": 1,
+                    "p_cost": 1,
+                    "registered": true,
+                }),
+            },
+        );
+        let error = fixture.login().await.unwrap_err();
+        assert!(matches!(error, Error::Incompatible(_)));
+        assert!(fixture.server.requests_to("/auth/login").is_empty());
+    }
+
+    #[tokio::test]
+    async fn legacy_server_gets_password_key_unless_it_offered_challenges() {
+        let fixture = Fixture::logged_out().await;
+        let legacy_info = json!({
+            "version": "0.9.0",
+            "api_version": 1,
+            "features": ["split", "adjust", "analysis"],
+        });
+        fixture.server.set_server_info(Some(legacy_info.clone()));
+        fixture.server.forget_verifier();
+        let error = fixture.login().await.unwrap_err();
+        assert!(matches!(error, Error::InsecureLogin(_)));
+        assert!(fixture.server.requests_to("/auth/login").is_empty());
+        login_allowing_insecure(&fixture).await.unwrap();
+        let login = fixture.server.requests_to("/auth/login").pop().unwrap();
+        assert!(login.body.contains(&digest(PASSWORD)));
+
+        let fixture = Fixture::logged_in().await;
+        fixture.server.set_server_info(Some(legacy_info));
+        let error = fixture.login().await.unwrap_err();
+        assert!(matches!(error, Error::Incompatible(_)));
+        assert_eq!(fixture.server.requests_to("/auth/login").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn legacy_account_registers_a_verifier_once() {
+        let fixture = Fixture::logged_out().await;
+        fixture.server.forget_verifier();
+        let error = fixture.login().await.unwrap_err();
+        assert!(matches!(error, Error::InsecureLogin(_)));
+        assert!(fixture.server.requests_to("/auth/login").is_empty());
+
+        login_allowing_insecure(&fixture).await.unwrap();
+        let registration = fixture.server.requests_to("/auth/login").pop().unwrap();
+        assert!(registration.body.contains("verifier"));
+
+        // From now on the legacy key is refused and the password proves itself.
+        let legacy = Client::new()
+            .post(format!("http://{}/auth/login", fixture.server.address()))
+            .json(&json!({ "key": digest(PASSWORD) }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(legacy.status(), 401);
+        fixture.login().await.unwrap();
+        let login = fixture.server.requests_to("/auth/login").pop().unwrap();
+        assert!(login.body.contains("proof"));
+        assert!(!login.body.contains(&digest(PASSWORD)));
+    }
+
+    #[tokio::test]
+    async fn login_with_wrong_password_is_unauthorized() {
+        let fixture = Fixture::logged_out().await;
+        let error = login_internal(
+            fixture.data_dir(),
+            "tester",
+            "wrong",
+            &fixture.server.address(),
+            None,
+            None,
+            false,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(error, Error::Unauthorized));
+        assert!(get_meta_internal(fixture.data_dir()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn incompatible_server_is_refused_before_login() {
+        let fixture = Fixture::logged_out().await;
+        fixture.server.set_server_info(Some(json!({
+            "version": "2.0.0",
+            "api_version": 2,
+            "features": [],
+        })));
+        let error = fixture.login().await.unwrap_err();
+        assert!(matches!(error, Error::Incompatible(_)));
+        assert!(fixture.server.requests_to("/auth/login").is_empty());
+    }
+
+    #[tokio::test]
+    async fn tokens_are_encrypted_at_rest() {
+        let fixture = Fixture::logged_in().await;
+        let meta_json = std::fs::read_to_string(fixture.data_dir().join("meta.json")).unwrap();
+        assert!(meta_json.contains(SEALED_FIELD));
+        assert!(!meta_json.contains("access-1"));
+        assert!(!meta_json.contains("refresh-1"));
+    }
+
+    #[tokio::test]
+    async fn summary_leaves_out_the_tokens() {
+        let fixture = Fixture::logged_in().await;
+        let meta = get_meta_internal(fixture.data_dir()).await.unwrap();
+        let summary = serde_json::to_string(&MetaSummary::from(meta)).unwrap();
+        assert!(summary.contains(USERNAME));
+        assert!(!summary.contains("access-1"));
+        assert!(!summary.contains("refresh-1"));
+    }
+
+    #[tokio::test]
+    async fn plaintext_tokens_are_migrated() {
+        let fixture = Fixture::logged_out().await;
+        let plaintext = json!({
+            "username": "tester",
+            "server_ip": fixture.server.address(),
+            "access_token": "access-1",
+            "refresh_token": "refresh-1",
+        });
+        std::fs::write(fixture.data_dir().join("meta.json"), plaintext.to_string()).unwrap();
+
+        let meta = get_meta_internal(fixture.data_dir()).await.unwrap();
+        assert_eq!(meta.access_token, "access-1");
+        let meta_json = std::fs::read_to_string(fixture.data_dir().join("meta.json")).unwrap();
+        assert!(!meta_json.contains("access-1"));
+        for entry in std::fs::read_dir(fixture.data_dir()).unwrap() {
+            let path = entry.unwrap().path();
+            let contents = std::fs::read_to_string(&path).unwrap_or_default();
+            assert!(!contents.contains("access-1"), "{:?} kept the token", path);
+        }
+        let meta = get_meta_internal(fixture.data_dir()).await.unwrap();
+        assert_eq!(meta.refresh_token, "refresh-1");
+    }
+
+    #[tokio::test]
+    async fn plaintext_tokens_stay_usable_without_a_secret_store() {
+        let fixture = Fixture::logged_out().await;
+        let data_dir = fixture.data_dir();
+        secrets::remove_test_keyring(data_dir);
+        let plaintext = json!({
+            "username": "tester",
+            "server_ip": fixture.server.address(),
+            "access_token": "access-1",
+            "refresh_token": "refresh-1",
+        });
+        std::fs::write(data_dir.join("meta.json"), plaintext.to_string()).unwrap();
+
+        let meta = get_meta_internal(data_dir).await.unwrap();
+        save_meta_internal(data_dir, &meta).await.unwrap();
+        assert!(plaintext_kept(data_dir).unwrap());
+        let meta_json = std::fs::read_to_string(data_dir.join("meta.json")).unwrap();
+        assert!(meta_json.contains("access-1"));
+
+        set_credentials_passphrase_internal(data_dir, Some("correct horse"))
+            .await
+            .unwrap();
+        let meta_json = std::fs::read_to_string(data_dir.join("meta.json")).unwrap();
+        assert!(!meta_json.contains("access-1"));
+    }
+
+    #[tokio::test]
+    async fn first_login_without_a_secret_store_needs_a_passphrase() {
+        let fixture = Fixture::logged_out().await;
+        let data_dir = fixture.data_dir();
+        let address = fixture.server.address();
+        secrets::remove_test_keyring(data_dir);
+
+        let error = login_internal(data_dir, USERNAME, PASSWORD, &address, None, None, false)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::Locked));
+        assert!(fixture.server.requests_to("/auth/login").is_empty());
+
+        login_internal(
+            data_dir,
+            USERNAME,
+            PASSWORD,
+            &address,
+            None,
+            Some("correct horse"),
+            false,
+        )
+        .await
+        .unwrap();
+        let sealed = read_sealed(data_dir).unwrap();
+        assert!(matches!(sealed.key, KeySource::Passphrase { .. }));
+        secrets::forget(&sealed.key).unwrap();
+        unlock_credentials_internal(data_dir, "correct horse")
+            .await
+            .unwrap();
+        let meta = get_meta_internal(data_dir).await.unwrap();
+        assert_eq!(meta.access_token, "access-1");
+    }
+
+    #[tokio::test]
+    async fn passphrase_protected_tokens_need_unlocking() {
+        let fixture = Fixture::logged_in().await;
+        let keyring_sealed = read_sealed(fixture.data_dir()).unwrap();
+        set_credentials_passphrase_internal(fixture.data_dir(), Some("correct horse"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            secrets::unseal(&keyring_sealed).await,
+            Err(Error::LoginExpired)
+        ));
+        // As after a restart, which only remembers the file.
+        let sealed = read_sealed(fixture.data_dir()).unwrap();
+        secrets::forget(&sealed.key).unwrap();
+
+        assert!(matches!(
+            get_meta_internal(fixture.data_dir()).await,
+            Err(Error::Locked)
+        ));
+        unlock_credentials_internal(fixture.data_dir(), "correct horse")
+            .await
+            .unwrap();
+        let meta = get_meta_internal(fixture.data_dir()).await.unwrap();
+        assert_eq!(meta.access_token, "access-1");
+
+        // Refreshed tokens stay under the passphrase.
+        save_meta_internal(fixture.data_dir(), &meta).await.unwrap();
+        assert_eq!(read_sealed(fixture.data_dir()).unwrap().key, sealed.key);
+    }
+
+    #[tokio::test]
+    async fn logout_revokes_and_wipes_account_data() {
+        let fixture = Fixture::logged_in().await;
+        let data_dir = fixture.data_dir();
+        std::fs::create_dir_all(data_dir.join("cache")).unwrap();
+        std::fs::write(data_dir.join("cache").join("state.json"), "{}").unwrap();
+        std::fs::write(data_dir.join("outbox.json"), "[]").unwrap();
+        std::fs::write(data_dir.join("network.json"), "{}").unwrap();
+
+        assert!(logout_internal(&fixture.client, data_dir).await.unwrap());
+        assert_eq!(fixture.server.revoked_count(), 1);
+        let revoke = fixture.server.requests_to("/auth/logout").pop().unwrap();
+        assert_eq!(revoke.body, "refresh-1");
+        for entry in ACCOUNT_ENTRIES {
+            assert!(!data_dir.join(entry).exists(), "{} survived", entry);
+        }
+        assert!(data_dir.join("network.json").exists());
+        assert!(matches!(
+            get_meta_internal(data_dir).await,
+            Err(Error::Io(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn logout_revokes_after_the_access_token_expired() {
+        let fixture = Fixture::logged_in().await;
+        fixture.server.expire_access_token();
+        assert!(logout_internal(&fixture.client, fixture.data_dir())
+            .await
+            .unwrap());
+        assert_eq!(fixture.server.revoked_count(), 1);
+        let revoke = fixture.server.requests_to("/auth/logout").pop().unwrap();
+        assert!(!revoke.headers.contains_key("authorization"));
+    }
+
+    #[tokio::test]
+    async fn logout_wipes_locally_when_server_is_unreachable() {
+        let fixture = Fixture::logged_in().await;
+        fixture.server.script("/auth/logout", Scripted::Disconnect);
+        assert!(!logout_internal(&fixture.client, fixture.data_dir())
+            .await
+            .unwrap());
+        assert_eq!(fixture.server.revoked_count(), 0);
+        assert!(!fixture.data_dir().join("meta.json").exists());
+    }
+
+    #[tokio::test]
+    async fn server_without_version_endpoint_is_treated_as_legacy() {
+        let fixture = Fixture::logged_out().await;
+        fixture.server.set_server_info(None);
+        fixture.server.forget_verifier();
+        login_allowing_insecure(&fixture).await.unwrap();
+        let meta = get_meta_internal(fixture.data_dir()).await.unwrap();
+        assert!(meta.server_info.unwrap().supports("analysis"));
+    }
+}
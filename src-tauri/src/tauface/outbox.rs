@@ -0,0 +1,65 @@
+use std::path::Path;
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tauri::State;
+
+use crate::{
+    error::Error, netutils::ApiClient, persist, schema, tauface::profiles::active_data_dir,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingWrite {
+    pub id: u64,
+    pub url_path: String,
+    pub body: Value,
+    pub queued_at: DateTime<Local>,
+    pub last_error: Option<String>,
+    // Set once the server refused the write. It no longer holds up the rest of
+    // the queue and stays listed until it is discarded.
+    #[serde(default)]
+    pub parked: bool,
+}
+
+#[tauri::command]
+pub async fn get_pending_writes(
+    app_handle: tauri::AppHandle,
+    client: State<'_, ApiClient>,
+) -> Result<Vec<PendingWrite>, Error> {
+    let data_dir = active_data_dir(&app_handle)?;
+    let _outbox = client.lock_outbox().await;
+    read_outbox(&data_dir)
+}
+
+#[tauri::command]
+pub async fn discard_pending_write(
+    id: u64,
+    app_handle: tauri::AppHandle,
+    client: State<'_, ApiClient>,
+) -> Result<(), Error> {
+    let data_dir = active_data_dir(&app_handle)?;
+    let _outbox = client.lock_outbox().await;
+    let mut pending = read_outbox(&data_dir)?;
+    let len = pending.len();
+    pending.retain(|write| write.id != id);
+    if pending.len() == len {
+        return Err(Error::InvalidInput(format!(
+            "No pending write with id {}",
+            id
+        )));
+    }
+    write_outbox(&data_dir, &pending)
+}
+
+pub fn read_outbox(data_dir: &Path) -> Result<Vec<PendingWrite>, Error> {
+    let outbox_path = data_dir.join("outbox.json");
+    if !outbox_path.exists() {
+        return Ok(Vec::new());
+    }
+    persist::read_versioned(&outbox_path, &schema::OUTBOX)
+}
+
+pub fn write_outbox(data_dir: &Path, pending: &[PendingWrite]) -> Result<(), Error> {
+    persist::write_versioned(&data_dir.join("outbox.json"), &schema::OUTBOX, pending)
+}
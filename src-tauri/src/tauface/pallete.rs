@@ -3,6 +3,7 @@ use tauri::Manager;
 use crate::{
     datatypes::{Palette, PaletteData},
     error::Error,
+    persist, schema,
 };
 
 #[tauri::command]
@@ -10,14 +11,11 @@ pub async fn save_palette(palette: PaletteData, app_handle: tauri::AppHandle) ->
     let cache_dir = app_handle
         .path()
         .app_local_data_dir()
-        .map_err(|e| Error::Client(e.to_string()))?;
+        .map_err(|e| Error::Io(e.to_string()))?;
     if !cache_dir.exists() {
-        std::fs::create_dir_all(&cache_dir).map_err(|e| Error::Client(e.to_string()))?;
+        std::fs::create_dir_all(&cache_dir).map_err(|e| Error::Io(e.to_string()))?;
     }
-    let palette_path = cache_dir.join("palette.json");
-    let palette_json = serde_json::to_string(&palette).map_err(|e| Error::Client(e.to_string()))?;
-    std::fs::write(&palette_path, palette_json).map_err(|e| Error::Client(e.to_string()))?;
-    Ok(())
+    persist::write_versioned(&cache_dir.join("palette.json"), &schema::PALETTE, &palette)
 }
 
 #[tauri::command]
@@ -25,7 +23,7 @@ pub async fn get_palette(app_handle: tauri::AppHandle) -> Result<PaletteData, Er
     let cache_dir = app_handle
         .path()
         .app_local_data_dir()
-        .map_err(|e| Error::Client(e.to_string()))?;
+        .map_err(|e| Error::Io(e.to_string()))?;
     let palette_path = cache_dir.join("palette.json");
     if !palette_path.exists() {
         let palette = Palette {
@@ -41,9 +39,5 @@ pub async fn get_palette(app_handle: tauri::AppHandle) -> Result<PaletteData, Er
         let palette_data = PaletteData { idx, palette };
         save_palette(palette_data, app_handle).await?;
     }
-    let palette_json =
-        std::fs::read_to_string(&palette_path).map_err(|e| Error::Client(e.to_string()))?;
-    let palette_data: PaletteData =
-        serde_json::from_str(&palette_json).map_err(|e| Error::Client(e.to_string()))?;
-    Ok(palette_data)
+    persist::read_versioned(&palette_path, &schema::PALETTE)
 }
@@ -0,0 +1,354 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use serde::{Deserialize, Serialize};
+use tauri::{Manager, State};
+
+use crate::{
+    error::Error,
+    netutils::ApiClient,
+    persist, schema, secrets,
+    tauface::{
+        meta::{read_meta_summary, sealed_key},
+        settings::get_network_settings_internal,
+    },
+};
+
+const PROFILES_FILE: &str = "profiles.json";
+const PROFILES_DIR: &str = "profiles";
+const DEFAULT_PROFILE: &str = "default";
+// Everything that belongs to one account and server. The palette and the
+// logs stay shared between profiles.
+const PROFILE_ENTRIES: [&str; 5] = [
+    "meta.json",
+    "network.json",
+    "outbox.json",
+    "server_ca.pem",
+    "cache",
+];
+
+// Names Windows reserves for devices, in any case and any directory.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+// Serializes reads and writes of profiles.json, including the one-off move of
+// a single account install into the default profile.
+static PROFILES: Mutex<()> = Mutex::new(());
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Profiles {
+    active: String,
+    profiles: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ProfileSummary {
+    pub name: String,
+    pub active: bool,
+    pub username: Option<String>,
+    pub server: Option<String>,
+}
+
+#[tauri::command]
+pub async fn list_profiles(app_handle: tauri::AppHandle) -> Result<Vec<ProfileSummary>, Error> {
+    let root_dir = root_dir(&app_handle)?;
+    list_profiles_internal(&root_dir)
+}
+
+pub fn list_profiles_internal(root_dir: &Path) -> Result<Vec<ProfileSummary>, Error> {
+    let profiles = read_profiles(root_dir)?;
+    let mut summaries = Vec::new();
+    for name in profiles.profiles {
+        let meta = read_meta_summary(&profile_dir(root_dir, &name)).ok();
+        summaries.push(ProfileSummary {
+            active: name == profiles.active,
+            username: meta.as_ref().map(|meta| meta.username.clone()),
+            server: meta.map(|meta| meta.server.url("")),
+            name,
+        });
+    }
+    Ok(summaries)
+}
+
+#[tauri::command]
+pub async fn add_profile(name: String, app_handle: tauri::AppHandle) -> Result<(), Error> {
+    let root_dir = root_dir(&app_handle)?;
+    add_profile_internal(&root_dir, &name)
+}
+
+#[tauri::command]
+pub async fn remove_profile(name: String, app_handle: tauri::AppHandle) -> Result<(), Error> {
+    let root_dir = root_dir(&app_handle)?;
+    remove_profile_internal(&root_dir, &name).await
+}
+
+#[tauri::command]
+pub async fn switch_profile(
+    name: String,
+    app_handle: tauri::AppHandle,
+    client: State<'_, ApiClient>,
+) -> Result<(), Error> {
+    let root_dir = root_dir(&app_handle)?;
+    switch_profile_internal(&root_dir, &name)?;
+    client.reset().await;
+    Ok(())
+}
+
+fn root_dir(app_handle: &tauri::AppHandle) -> Result<PathBuf, Error> {
+    app_handle
+        .path()
+        .app_local_data_dir()
+        .map_err(|e| Error::Io(e.to_string()))
+}
+
+// The data dir of the active profile, which every per-account command and
+// background task works in.
+pub fn active_data_dir(app_handle: &tauri::AppHandle) -> Result<PathBuf, Error> {
+    active_data_dir_internal(&root_dir(app_handle)?)
+}
+
+pub fn active_data_dir_internal(root_dir: &Path) -> Result<PathBuf, Error> {
+    let profiles = read_profiles(root_dir)?;
+    let data_dir = profile_dir(root_dir, &profiles.active);
+    if !data_dir.exists() {
+        std::fs::create_dir_all(&data_dir).map_err(|e| Error::Io(e.to_string()))?;
+    }
+    Ok(data_dir)
+}
+
+fn profile_dir(root_dir: &Path, name: &str) -> PathBuf {
+    root_dir.join(PROFILES_DIR).join(name)
+}
+
+fn validate_name(name: &str) -> Result<(), Error> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | ' '))
+        && name.trim() == name
+        && !RESERVED_NAMES
+            .iter()
+            .any(|reserved| reserved.eq_ignore_ascii_case(name));
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidInput(format!(
+            "Profile names may only use letters, digits, spaces, '-' and '_': {}",
+            name
+        )))
+    }
+}
+
+fn read_profiles(root_dir: &Path) -> Result<Profiles, Error> {
+    let _profiles = PROFILES.lock().map_err(|e| Error::Client(e.to_string()))?;
+    read_profiles_locked(root_dir)
+}
+
+// Callers must hold the profiles lock.
+fn read_profiles_locked(root_dir: &Path) -> Result<Profiles, Error> {
+    let profiles_path = root_dir.join(PROFILES_FILE);
+    if profiles_path.exists() {
+        return persist::read_versioned(&profiles_path, &schema::PROFILES);
+    }
+    migrate_single_profile(root_dir)
+}
+
+fn write_profiles(root_dir: &Path, profiles: &Profiles) -> Result<(), Error> {
+    persist::write_versioned(&root_dir.join(PROFILES_FILE), &schema::PROFILES, profiles)
+}
+
+// Installs from before profiles kept one account directly in the data dir,
+// which becomes the default profile. Callers must hold the profiles lock.
+fn migrate_single_profile(root_dir: &Path) -> Result<Profiles, Error> {
+    let data_dir = profile_dir(root_dir, DEFAULT_PROFILE);
+    std::fs::create_dir_all(&data_dir).map_err(|e| Error::Io(e.to_string()))?;
+    for entry in PROFILE_ENTRIES {
+        let legacy_path = root_dir.join(entry);
+        if legacy_path.exists() {
+            std::fs::rename(&legacy_path, data_dir.join(entry))
+                .map_err(|e| Error::Io(e.to_string()))?;
+        }
+    }
+    let profiles = Profiles {
+        active: DEFAULT_PROFILE.to_string(),
+        profiles: vec![DEFAULT_PROFILE.to_string()],
+    };
+    write_profiles(root_dir, &profiles)?;
+    Ok(profiles)
+}
+
+fn update_profiles<F>(root_dir: &Path, update: F) -> Result<(), Error>
+where
+    F: FnOnce(&mut Profiles) -> Result<(), Error>,
+{
+    let _profiles = PROFILES.lock().map_err(|e| Error::Client(e.to_string()))?;
+    let mut profiles = read_profiles_locked(root_dir)?;
+    update(&mut profiles)?;
+    write_profiles(root_dir, &profiles)
+}
+
+pub fn add_profile_internal(root_dir: &Path, name: &str) -> Result<(), Error> {
+    validate_name(name)?;
+    update_profiles(root_dir, |profiles| {
+        // Case insensitive file systems would give both the same directory.
+        if profiles
+            .profiles
+            .iter()
+            .any(|profile| profile.eq_ignore_ascii_case(name))
+        {
+            return Err(Error::InvalidInput(format!(
+                "Profile {} already exists",
+                name
+            )));
+        }
+        std::fs::create_dir_all(profile_dir(root_dir, name))
+            .map_err(|e| Error::Io(e.to_string()))?;
+        profiles.profiles.push(name.to_string());
+        Ok(())
+    })
+}
+
+pub async fn remove_profile_internal(root_dir: &Path, name: &str) -> Result<(), Error> {
+    update_profiles(root_dir, |profiles| {
+        if profiles.active == name {
+            return Err(Error::InvalidInput(
+                "Switch to another profile before removing this one".to_string(),
+            ));
+        }
+        let len = profiles.profiles.len();
+        profiles.profiles.retain(|profile| profile != name);
+        if profiles.profiles.len() == len {
+            return Err(Error::InvalidInput(format!("No profile named {}", name)));
+        }
+        Ok(())
+    })?;
+    let data_dir = profile_dir(root_dir, name);
+    // The secret store entries are keyed by the data dir and would outlive it.
+    if let Some(key) = sealed_key(&data_dir) {
+        secrets::destroy(&key).await?;
+    }
+    let proxy_password = get_network_settings_internal(&data_dir)
+        .ok()
+        .and_then(|settings| settings.proxy.sealed_password);
+    if let Some(sealed) = proxy_password {
+        secrets::destroy(&sealed.key).await?;
+    }
+    if data_dir.exists() {
+        std::fs::remove_dir_all(data_dir).map_err(|e| Error::Io(e.to_string()))?;
+    }
+    Ok(())
+}
+
+pub fn switch_profile_internal(root_dir: &Path, name: &str) -> Result<(), Error> {
+    update_profiles(root_dir, |profiles| {
+        if !profiles.profiles.iter().any(|profile| profile == name) {
+            return Err(Error::InvalidInput(format!("No profile named {}", name)));
+        }
+        profiles.active = name.to_string();
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::fake_server::TempDir;
+
+    #[test]
+    fn single_account_install_becomes_default_profile() {
+        let root = TempDir::new();
+        std::fs::write(root.path().join("meta.json"), "{}").unwrap();
+        std::fs::create_dir_all(root.path().join("cache")).unwrap();
+        std::fs::write(root.path().join("palette.json"), "{}").unwrap();
+
+        let data_dir = active_data_dir_internal(root.path()).unwrap();
+        assert_eq!(data_dir, root.path().join("profiles").join("default"));
+        assert!(data_dir.join("meta.json").exists());
+        assert!(data_dir.join("cache").exists());
+        assert!(!root.path().join("meta.json").exists());
+        assert!(root.path().join("palette.json").exists());
+    }
+
+    #[tokio::test]
+    async fn profiles_have_separate_data_dirs() {
+        let root = TempDir::new();
+        let personal = active_data_dir_internal(root.path()).unwrap();
+        add_profile_internal(root.path(), "work").unwrap();
+        switch_profile_internal(root.path(), "work").unwrap();
+        let work = active_data_dir_internal(root.path()).unwrap();
+        assert_ne!(personal, work);
+        let sealed = secrets::seal(&secrets::keyring_source(&work), b"tokens")
+            .await
+            .unwrap();
+        std::fs::write(
+            work.join("meta.json"),
+            json!({ "sealed": sealed }).to_string(),
+        )
+        .unwrap();
+
+        assert!(matches!(
+            remove_profile_internal(root.path(), "work").await,
+            Err(Error::InvalidInput(_))
+        ));
+        switch_profile_internal(root.path(), DEFAULT_PROFILE).unwrap();
+        remove_profile_internal(root.path(), "work").await.unwrap();
+        assert!(!work.exists());
+        assert!(matches!(
+            secrets::unseal(&sealed).await,
+            Err(Error::LoginExpired)
+        ));
+        assert_eq!(
+            read_profiles(root.path()).unwrap().profiles,
+            [DEFAULT_PROFILE]
+        );
+    }
+
+    #[test]
+    fn listing_profiles_leaves_their_credentials_alone() {
+        let root = TempDir::new();
+        let data_dir = active_data_dir_internal(root.path()).unwrap();
+        // Sealed with a key that is not in any secret store.
+        let sealed = json!({
+            "username": "tester",
+            "server_ip": "127.0.0.1:8080",
+            "sealed": { "not": "unsealable" },
+        })
+        .to_string();
+        std::fs::write(data_dir.join("meta.json"), &sealed).unwrap();
+
+        let summaries = list_profiles_internal(root.path()).unwrap();
+        assert_eq!(summaries[0].username.as_deref(), Some("tester"));
+        assert!(summaries[0].server.is_some());
+        assert_eq!(
+            std::fs::read_to_string(data_dir.join("meta.json")).unwrap(),
+            sealed
+        );
+    }
+
+    #[test]
+    fn profile_names_must_be_safe_directory_names() {
+        let root = TempDir::new();
+        for name in ["", "../escape", "a/b", " padded", "CON", "nul", "Com1"] {
+            assert!(matches!(
+                add_profile_internal(root.path(), name),
+                Err(Error::InvalidInput(_))
+            ));
+        }
+        add_profile_internal(root.path(), "Work laptop").unwrap();
+        for name in ["Work laptop", "work LAPTOP"] {
+            assert!(matches!(
+                add_profile_internal(root.path(), name),
+                Err(Error::InvalidInput(_))
+            ));
+        }
+        add_profile_internal(root.path(), "Console").unwrap();
+    }
+}
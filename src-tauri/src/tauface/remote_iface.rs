@@ -1,29 +1,60 @@
-use chrono::{DateTime, Local, NaiveTime};
+use std::path::Path;
+
+use chrono::{DateTime, Local, NaiveDate, NaiveTime};
 use serde::{Deserialize, Serialize};
-use tauri::Manager;
+use tauri::{Manager, State};
 
 use crate::{
+    cache::{self, cache_key, read_through, Cached},
     datatypes::{
         AdjustTimeBlockQuery, AdjustTimeBlockQueryJs, Analysis, BlockType, CurrentBlock, HomeData,
         NewBlockType, SplitTimeBlockQuery, SplitTimeBlockQueryJs, TimeBlock,
     },
-    netutils::{make_get_request, make_post_request},
+    handshake::{require_feature, FEATURE_ADJUST, FEATURE_ANALYSIS, FEATURE_SPLIT},
+    netutils::ApiClient,
+    tauface::profiles::active_data_dir,
 };
 
 use crate::error::Error;
 
+pub const HOME_DATA_KEY: &str = "/state";
+
+pub fn history_key(date: NaiveDate) -> String {
+    cache_key("/timeblock/get", &[("date", &date.to_string())])
+}
+
+fn mark_days_dirty(data_dir: &Path, times: &[DateTime<Local>]) -> Result<(), Error> {
+    cache::mark_dirty(data_dir, HOME_DATA_KEY)?;
+    for time in times {
+        cache::mark_dirty(data_dir, &history_key(time.date_naive()))?;
+    }
+    Ok(())
+}
+
 #[tauri::command]
-pub async fn get_home_data(app_handle: tauri::AppHandle) -> Result<HomeData, Error> {
-    let data_dir = app_handle
-        .path()
-        .app_local_data_dir()
-        .map_err(|e| Error::Client(e.to_string()))?;
-    let mut home_data: HomeData = make_get_request("/state", &data_dir, None).await?;
+pub async fn get_home_data(app_handle: tauri::AppHandle) -> Result<Cached<HomeData>, Error> {
+    let data_dir = active_data_dir(&app_handle)?;
+    read_through(
+        &app_handle,
+        &data_dir,
+        HOME_DATA_KEY,
+        "home-data-refreshed",
+        false,
+        |app_handle, data_dir| async move {
+            let client = app_handle.state::<ApiClient>();
+            fetch_home_data(&client, &data_dir).await
+        },
+    )
+    .await
+}
+
+pub async fn fetch_home_data(client: &ApiClient, data_dir: &Path) -> Result<HomeData, Error> {
+    let mut home_data: HomeData = client.make_get_request("/state", data_dir, None).await?;
     home_data.daydata.reverse();
     Ok(home_data)
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct HistoryData {
     pub daydata: Vec<TimeBlock>,
     pub blocktypes: Vec<BlockType>,
@@ -32,20 +63,43 @@ pub struct HistoryData {
 #[tauri::command]
 pub async fn get_day_history(
     date: DateTime<Local>,
+    request_id: Option<String>,
     app_handle: tauri::AppHandle,
-) -> Result<HistoryData, Error> {
-    let data_dir = app_handle
-        .path()
-        .app_local_data_dir()
-        .map_err(|e| Error::Client(e.to_string()))?;
-    let mut time_blocks: Vec<TimeBlock> = make_get_request(
-        "/timeblock/get",
+) -> Result<Cached<HistoryData>, Error> {
+    let data_dir = active_data_dir(&app_handle)?;
+    let is_past_day = date.date_naive() < Local::now().date_naive();
+    read_through(
+        &app_handle,
         &data_dir,
-        Some(&[("date", &date.to_rfc3339())]),
+        &history_key(date.date_naive()),
+        "day-history-refreshed",
+        is_past_day,
+        move |app_handle, data_dir| async move {
+            let client = app_handle.state::<ApiClient>();
+            client
+                .cancellable(request_id, fetch_day_history(&client, &data_dir, date))
+                .await
+        },
     )
-    .await?;
+    .await
+}
+
+pub async fn fetch_day_history(
+    client: &ApiClient,
+    data_dir: &Path,
+    date: DateTime<Local>,
+) -> Result<HistoryData, Error> {
+    let mut time_blocks: Vec<TimeBlock> = client
+        .make_get_request(
+            "/timeblock/get",
+            data_dir,
+            Some(&[("date", &date.to_rfc3339())]),
+        )
+        .await?;
     time_blocks.reverse();
-    let blocktypes = make_get_request("/blocktype/get", &data_dir, None).await?;
+    let blocktypes = client
+        .make_get_request("/blocktype/get", data_dir, None)
+        .await?;
     let res = HistoryData {
         daydata: time_blocks,
         blocktypes,
@@ -57,67 +111,116 @@ pub async fn get_day_history(
 pub async fn get_analysis(
     start_date: DateTime<Local>,
     end_date: DateTime<Local>,
+    request_id: Option<String>,
     app_handle: tauri::AppHandle,
-) -> Result<Analysis, Error> {
-    let data_dir = app_handle
-        .path()
-        .app_local_data_dir()
-        .map_err(|e| Error::Client(e.to_string()))?;
-    let analysis = make_get_request(
+) -> Result<Cached<Analysis>, Error> {
+    let data_dir = active_data_dir(&app_handle)?;
+    require_feature(&data_dir, FEATURE_ANALYSIS).await?;
+    let key = cache_key(
         "/analysis",
-        &data_dir,
-        Some(&[
+        &[
             ("start", &start_date.to_rfc3339()),
             ("end", &end_date.to_rfc3339()),
-        ]),
+        ],
+    );
+    read_through(
+        &app_handle,
+        &data_dir,
+        &key,
+        "analysis-refreshed",
+        false,
+        move |app_handle, data_dir| async move {
+            let client = app_handle.state::<ApiClient>();
+            client
+                .cancellable(
+                    request_id,
+                    fetch_analysis(&client, &data_dir, start_date, end_date),
+                )
+                .await
+        },
     )
-    .await?;
-    Ok(analysis)
+    .await
+}
+
+pub async fn fetch_analysis(
+    client: &ApiClient,
+    data_dir: &Path,
+    start_date: DateTime<Local>,
+    end_date: DateTime<Local>,
+) -> Result<Analysis, Error> {
+    client
+        .make_get_request(
+            "/analysis",
+            data_dir,
+            Some(&[
+                ("start", &start_date.to_rfc3339()),
+                ("end", &end_date.to_rfc3339()),
+            ]),
+        )
+        .await
+}
+
+#[tauri::command]
+pub async fn cancel_request(request_id: String, client: State<'_, ApiClient>) -> Result<(), Error> {
+    if client.cancel(&request_id).await {
+        Ok(())
+    } else {
+        Err(Error::InvalidInput(format!(
+            "No request in flight with id {}",
+            request_id
+        )))
+    }
 }
 
 #[tauri::command]
 pub async fn post_next_block(
     data: CurrentBlock,
     app_handle: tauri::AppHandle,
+    client: State<'_, ApiClient>,
 ) -> Result<(), Error> {
-    let data_dir = app_handle
-        .path()
-        .app_local_data_dir()
-        .map_err(|e| Error::Client(e.to_string()))?;
-    make_post_request("/timeblock/next", &data_dir, &data).await
+    let data_dir = active_data_dir(&app_handle)?;
+    client
+        .make_queued_post_request("/timeblock/next", &data_dir, &data)
+        .await?;
+    mark_days_dirty(&data_dir, &[Local::now()])
+}
+
+fn time_on_day(day: DateTime<Local>, time: &str, what: &str) -> Result<DateTime<Local>, Error> {
+    let parts = time
+        .split(':')
+        .map(|part| part.parse::<u32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| Error::InvalidInput(format!("Failed to parse {}", what)))?;
+    let [hour, minute, second] = parts[..] else {
+        return Err(Error::InvalidInput(format!(
+            "Expected {} as HH:MM:SS",
+            what
+        )));
+    };
+    let time = NaiveTime::from_hms_opt(hour, minute, second)
+        .ok_or(Error::InvalidInput(format!("Failed to create {}", what)))?;
+    day.with_time(time)
+        .single()
+        .ok_or(Error::InvalidInput(format!(
+            "Failed to find unique {}",
+            what
+        )))
 }
 
 #[tauri::command]
 pub async fn post_split_block(
     app_handle: tauri::AppHandle,
+    client: State<'_, ApiClient>,
     data: SplitTimeBlockQueryJs,
 ) -> Result<(), Error> {
-    let data_dir = app_handle
-        .path()
-        .app_local_data_dir()
-        .map_err(|e| Error::Client(e.to_string()))?;
-    let splits = data.split_time.split(":").collect::<Vec<&str>>();
-    println!("{:?}", &data);
-    let split_time = data
-        .start_time
-        .with_time(
-            NaiveTime::from_hms_opt(
-                splits[0]
-                    .parse()
-                    .map_err(|_| Error::Client("Failed to parse split time hour".to_string()))?,
-                splits[1]
-                    .parse()
-                    .map_err(|_| Error::Client("Failed to parse split time minute".to_string()))?,
-                splits[2]
-                    .parse()
-                    .map_err(|_| Error::Client("Failed to parse split time second".to_string()))?,
-            )
-            .ok_or(Error::Client("Failed to create split time".to_string()))?,
-        )
-        .single()
-        .ok_or(Error::Client(
-            "Failed to find unique split time".to_string(),
-        ))?;
+    let data_dir = active_data_dir(&app_handle)?;
+    log::debug!(
+        "Splitting the block from {} to {}",
+        data.start_time,
+        data.end_time
+    );
+    require_feature(&data_dir, FEATURE_SPLIT).await?;
+    let split_time = time_on_day(data.start_time, &data.split_time, "split time")?;
     let data = SplitTimeBlockQuery {
         start_time: data.start_time,
         end_time: data.end_time,
@@ -127,61 +230,22 @@ pub async fn post_split_block(
         before_block_type_id: data.before_block_type_id,
         after_block_type_id: data.after_block_type_id,
     };
-    make_post_request("/timeblock/split", &data_dir, &data).await
+    client
+        .make_queued_post_request("/timeblock/split", &data_dir, &data)
+        .await?;
+    mark_days_dirty(&data_dir, &[data.start_time, data.end_time])
 }
 
 #[tauri::command]
 pub async fn post_adjust_block(
     app_handle: tauri::AppHandle,
+    client: State<'_, ApiClient>,
     data: AdjustTimeBlockQueryJs,
 ) -> Result<(), Error> {
-    let data_dir = app_handle
-        .path()
-        .app_local_data_dir()
-        .map_err(|e| Error::Client(e.to_string()))?;
-    let new_start_splits = data.new_start_time.split(":").collect::<Vec<&str>>();
-    let new_start_time = data
-        .start_time
-        .with_time(
-            NaiveTime::from_hms_opt(
-                new_start_splits[0].parse().map_err(|_| {
-                    Error::Client("Failed to parse new start time hour".to_string())
-                })?,
-                new_start_splits[1].parse().map_err(|_| {
-                    Error::Client("Failed to parse new start time minute".to_string())
-                })?,
-                new_start_splits[2].parse().map_err(|_| {
-                    Error::Client("Failed to parse new start time second".to_string())
-                })?,
-            )
-            .ok_or(Error::Client("Failed to create new start time".to_string()))?,
-        )
-        .single()
-        .ok_or(Error::Client(
-            "Failed to find unique new start time".to_string(),
-        ))?;
-
-    let new_end_splits = data.new_end_time.split(":").collect::<Vec<&str>>();
-    let new_end_time = data
-        .end_time
-        .with_time(
-            NaiveTime::from_hms_opt(
-                new_end_splits[0]
-                    .parse()
-                    .map_err(|_| Error::Client("Failed to parse new end time hour".to_string()))?,
-                new_end_splits[1].parse().map_err(|_| {
-                    Error::Client("Failed to parse new end time minute".to_string())
-                })?,
-                new_end_splits[2].parse().map_err(|_| {
-                    Error::Client("Failed to parse new end time second".to_string())
-                })?,
-            )
-            .ok_or(Error::Client("Failed to create new end time".to_string()))?,
-        )
-        .single()
-        .ok_or(Error::Client(
-            "Failed to find unique new end time".to_string(),
-        ))?;
+    let data_dir = active_data_dir(&app_handle)?;
+    require_feature(&data_dir, FEATURE_ADJUST).await?;
+    let new_start_time = time_on_day(data.start_time, &data.new_start_time, "new start time")?;
+    let new_end_time = time_on_day(data.end_time, &data.new_end_time, "new end time")?;
 
     let data = AdjustTimeBlockQuery {
         start_time: data.start_time,
@@ -192,29 +256,154 @@ pub async fn post_adjust_block(
         block_type_id: data.block_type_id,
     };
 
-    make_post_request("/timeblock/adjust", &data_dir, &data).await
+    client
+        .make_queued_post_request("/timeblock/adjust", &data_dir, &data)
+        .await?;
+    mark_days_dirty(
+        &data_dir,
+        &[
+            data.start_time,
+            data.end_time,
+            data.new_start_time,
+            data.new_end_time,
+        ],
+    )
 }
 
 #[tauri::command]
 pub async fn post_change_current(
     data: CurrentBlock,
     app_handle: tauri::AppHandle,
+    client: State<'_, ApiClient>,
 ) -> Result<(), Error> {
-    let data_dir = app_handle
-        .path()
-        .app_local_data_dir()
-        .map_err(|e| Error::Client(e.to_string()))?;
-    make_post_request("/currentblock/change", &data_dir, &data).await
+    let data_dir = active_data_dir(&app_handle)?;
+    change_current_internal(&client, &data_dir, &data).await
+}
+
+pub async fn change_current_internal(
+    client: &ApiClient,
+    data_dir: &Path,
+    data: &CurrentBlock,
+) -> Result<(), Error> {
+    client
+        .make_queued_post_request("/currentblock/change", data_dir, data)
+        .await?;
+    mark_days_dirty(data_dir, &[Local::now()])
 }
 
 #[tauri::command]
 pub async fn post_new_block_type(
     data: NewBlockType,
     app_handle: tauri::AppHandle,
+    client: State<'_, ApiClient>,
 ) -> Result<(), Error> {
-    let data_dir = app_handle
-        .path()
-        .app_local_data_dir()
-        .map_err(|e| Error::Client(e.to_string()))?;
-    make_post_request("/blocktype/new", &data_dir, &data).await
+    let data_dir = active_data_dir(&app_handle)?;
+    client
+        .make_queued_post_request("/blocktype/new", &data_dir, &data)
+        .await?;
+    cache::mark_dirty(&data_dir, HOME_DATA_KEY)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    use super::*;
+    use crate::fake_server::{sample_home_data, Fixture};
+
+    #[tokio::test]
+    async fn home_data_is_returned_newest_first() {
+        let fixture = Fixture::logged_in().await;
+        let home_data = fetch_home_data(&fixture.client, fixture.data_dir())
+            .await
+            .unwrap();
+        let titles: Vec<_> = home_data.daydata.iter().map(|block| &block.title).collect();
+        assert_eq!(titles, ["Second", "First"]);
+    }
+
+    #[tokio::test]
+    async fn day_history_queries_the_requested_date() {
+        let fixture = Fixture::logged_in().await;
+        let mut home_data = sample_home_data();
+        home_data["blocktypes"] = json!([]);
+        fixture.server.set_home_data(home_data);
+        let date = Local.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let history = fetch_day_history(&fixture.client, fixture.data_dir(), date)
+            .await
+            .unwrap();
+        assert_eq!(history.daydata.len(), 2);
+        assert!(history.blocktypes.is_empty());
+
+        let request = fixture.server.requests_to("/timeblock/get").pop().unwrap();
+        assert!(request.query.starts_with("date=2024-01-01"));
+    }
+
+    #[tokio::test]
+    async fn analysis_is_fetched_after_token_expiry() {
+        let fixture = Fixture::logged_in().await;
+        fixture.server.expire_access_token();
+        let start = Local.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let end = Local.with_ymd_and_hms(2024, 1, 7, 0, 0, 0).unwrap();
+        let analysis = fetch_analysis(&fixture.client, fixture.data_dir(), start, end)
+            .await
+            .unwrap();
+        assert_eq!(analysis.percentages, [75.0, 25.0]);
+        assert_eq!(fixture.server.refresh_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn current_block_change_reaches_the_server() {
+        let fixture = Fixture::logged_in().await;
+        let current = CurrentBlock {
+            block_type_id: 2,
+            current_block_name: "Lunch".to_string(),
+        };
+        change_current_internal(&fixture.client, fixture.data_dir(), &current)
+            .await
+            .unwrap();
+        assert_eq!(fixture.server.requests_to("/currentblock/change").len(), 1);
+        let home_data = fetch_home_data(&fixture.client, fixture.data_dir())
+            .await
+            .unwrap();
+        assert_eq!(home_data.currentblock.current_block_name, "Lunch");
+        assert_eq!(home_data.currentblock.block_type_id, 2);
+    }
+
+    #[tokio::test]
+    async fn features_missing_on_the_server_are_unsupported() {
+        let fixture = Fixture::logged_out().await;
+        fixture.server.set_server_info(Some(json!({
+            "version": "1.1.0",
+            "api_version": 1,
+            "features": ["split", "challenge_login"],
+        })));
+        fixture.login().await.unwrap();
+        require_feature(fixture.data_dir(), FEATURE_SPLIT)
+            .await
+            .unwrap();
+        let error = require_feature(fixture.data_dir(), FEATURE_ADJUST)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::Unsupported(_)));
+    }
+
+    #[test]
+    fn time_of_day_is_parsed_onto_the_block_date() {
+        let day = Local.with_ymd_and_hms(2024, 1, 1, 8, 0, 0).unwrap();
+        let split = time_on_day(day, "09:30:15", "split time").unwrap();
+        assert_eq!(
+            split,
+            Local.with_ymd_and_hms(2024, 1, 1, 9, 30, 15).unwrap()
+        );
+        assert!(matches!(
+            time_on_day(day, "09:30", "split time"),
+            Err(Error::InvalidInput(_))
+        ));
+        assert!(matches!(
+            time_on_day(day, "25:00:00", "split time"),
+            Err(Error::InvalidInput(_))
+        ));
+    }
 }
@@ -0,0 +1,167 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tauri::State;
+
+use crate::{
+    error::Error, netutils::ApiClient, persist, proxy::ProxySettings, schema, secrets,
+    tauface::profiles::active_data_dir,
+};
+
+// A zero timeout fails every request at once, and past a few minutes a dead
+// server looks like a hung app.
+const MAX_TIMEOUT_SECS: u64 = 300;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct NetworkSettings {
+    pub connect_timeout_secs: u64,
+    pub read_timeout_secs: u64,
+    pub max_retries: u32,
+    pub poll_interval_secs: u64,
+    pub proxy: ProxySettings,
+}
+
+impl Default for NetworkSettings {
+    fn default() -> Self {
+        Self {
+            connect_timeout_secs: 10,
+            read_timeout_secs: 30,
+            max_retries: 3,
+            poll_interval_secs: 30,
+            proxy: ProxySettings::default(),
+        }
+    }
+}
+
+#[tauri::command]
+pub async fn get_network_settings(app_handle: tauri::AppHandle) -> Result<NetworkSettings, Error> {
+    let data_dir = active_data_dir(&app_handle)?;
+    open_network_settings(&data_dir).await
+}
+
+#[tauri::command]
+pub async fn save_network_settings(
+    settings: NetworkSettings,
+    app_handle: tauri::AppHandle,
+    client: State<'_, ApiClient>,
+) -> Result<(), Error> {
+    let data_dir = active_data_dir(&app_handle)?;
+    save_network_settings_internal(&data_dir, &settings).await?;
+    client.reset().await;
+    Ok(())
+}
+
+// The settings as stored, with the proxy password still sealed.
+pub fn get_network_settings_internal(data_dir: &Path) -> Result<NetworkSettings, Error> {
+    let settings_path = data_dir.join("network.json");
+    if !settings_path.exists() {
+        return Ok(NetworkSettings::default());
+    }
+    persist::read_versioned(&settings_path, &schema::NETWORK)
+}
+
+// The settings with the proxy password unsealed. One stored in plaintext by
+// an older version is sealed on the way.
+pub async fn open_network_settings(data_dir: &Path) -> Result<NetworkSettings, Error> {
+    let mut settings = get_network_settings_internal(data_dir)?;
+    match settings.proxy.sealed_password.take() {
+        Some(sealed) => match secrets::unseal(&sealed).await {
+            Ok(password) => {
+                settings.proxy.password =
+                    Some(String::from_utf8(password).map_err(|e| Error::Decode(e.to_string()))?);
+            }
+            // The proxy will ask for it, which is better than no requests at all.
+            Err(e) => log::warn!("Could not unseal the proxy password: {}", e),
+        },
+        None if settings.proxy.password.is_some() => {
+            if let Err(e) = save_network_settings_internal(data_dir, &settings).await {
+                log::warn!("Proxy password stays unencrypted: {}", e);
+            }
+        }
+        None => {}
+    }
+    Ok(settings)
+}
+
+fn validate_timeouts(settings: &NetworkSettings) -> Result<(), Error> {
+    for (name, secs) in [
+        ("Connect timeout", settings.connect_timeout_secs),
+        ("Read timeout", settings.read_timeout_secs),
+    ] {
+        if !(1..=MAX_TIMEOUT_SECS).contains(&secs) {
+            return Err(Error::InvalidInput(format!(
+                "{} must be between 1 and {} seconds",
+                name, MAX_TIMEOUT_SECS
+            )));
+        }
+    }
+    Ok(())
+}
+
+// The proxy password is sealed under its own secret store entry, as it has to
+// survive a logout.
+pub async fn save_network_settings_internal(
+    data_dir: &Path,
+    settings: &NetworkSettings,
+) -> Result<(), Error> {
+    validate_timeouts(settings)?;
+    let settings_path = data_dir.join("network.json");
+    let had_plaintext = get_network_settings_internal(data_dir)
+        .is_ok_and(|previous| previous.proxy.password.is_some());
+    let mut settings_value =
+        serde_json::to_value(settings).map_err(|e| Error::Client(e.to_string()))?;
+    if let Some(proxy) = settings_value
+        .get_mut("proxy")
+        .and_then(Value::as_object_mut)
+    {
+        proxy.remove("password");
+        if let Some(password) = settings.proxy.password.as_deref().filter(|p| !p.is_empty()) {
+            let source = secrets::settings_keyring_source(data_dir);
+            let sealed = match secrets::seal(&source, password.as_bytes()).await {
+                Err(Error::Locked) => {
+                    return Err(Error::Unsupported(
+                        "Saving a proxy password needs the system secret store".to_string(),
+                    ))
+                }
+                sealed => sealed?,
+            };
+            let sealed = serde_json::to_value(sealed).map_err(|e| Error::Client(e.to_string()))?;
+            proxy.insert("sealed_password".to_string(), sealed);
+        }
+    }
+    persist::write_versioned(&settings_path, &schema::NETWORK, &settings_value)?;
+    // The backup of the previous file would still hold the password.
+    if had_plaintext {
+        persist::discard_backup(&settings_path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::fake_server::TempDir;
+
+    #[tokio::test]
+    async fn timeouts_out_of_range_are_refused() {
+        let data_dir = TempDir::new();
+        for (connect_timeout_secs, read_timeout_secs) in [(0, 30), (10, 0), (10, 301)] {
+            let settings = NetworkSettings {
+                connect_timeout_secs,
+                read_timeout_secs,
+                ..NetworkSettings::default()
+            };
+            assert!(matches!(
+                save_network_settings_internal(data_dir.path(), &settings).await,
+                Err(Error::InvalidInput(_))
+            ));
+        }
+        assert!(!data_dir.path().join("network.json").exists());
+        save_network_settings_internal(data_dir.path(), &NetworkSettings::default())
+            .await
+            .unwrap();
+    }
+}
@@ -3,7 +3,11 @@ use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use crate::datatypes::SunHours;
+use crate::{
+    datatypes::SunHours,
+    netutils::{build_external_client, map_send_error},
+    tauface::profiles::active_data_dir,
+};
 
 use crate::error::Error;
 
@@ -19,22 +23,15 @@ pub struct Locator {
     pub location: String,
 }
 
-pub async fn find(ip: &str) -> Result<Locator, Error> {
+// An empty `ip` looks up the address the request comes from.
+pub async fn find(client: &Client, ip: &str) -> Result<Locator, Error> {
     let uri = format!("http://ip-api.com/json/{}", &ip);
 
-    let client = Client::new();
-    let local_data_response = client
-        .get(&uri)
-        .send()
-        .await
-        .map_err(|e| Error::Client(e.to_string()))?;
+    let local_data_response = client.get(&uri).send().await.map_err(map_send_error)?;
 
-    let local_data = local_data_response
-        .text()
-        .await
-        .map_err(|e| Error::Client(e.to_string()))?;
+    let local_data = local_data_response.text().await.map_err(map_send_error)?;
     let local_body: Value =
-        serde_json::from_str(&local_data).map_err(|e| Error::Client(e.to_string()))?;
+        serde_json::from_str(&local_data).map_err(|e| Error::Decode(e.to_string()))?;
     let result = Locator {
         ip: local_body["query"].to_string(),
         latitude: local_body["lat"].to_string(),
@@ -67,20 +64,19 @@ struct SunApiResults {
 }
 
 #[tauri::command]
-pub async fn get_sun_hours() -> Result<SunHours, Error> {
-    let public_ip = public_ip::addr()
-        .await
-        .ok_or(Error::Client("No public ip".to_string()))?;
-    let locinfo = find(&public_ip.to_string()).await?;
+pub async fn get_sun_hours(app_handle: tauri::AppHandle) -> Result<SunHours, Error> {
+    let data_dir = active_data_dir(&app_handle)?;
+    let client = build_external_client(&data_dir).await?;
+    let locinfo = find(&client, "").await?;
 
     let lat = locinfo
         .latitude
         .parse::<f64>()
-        .map_err(|_| Error::Client("Invalid latitude".to_string()))?;
+        .map_err(|_| Error::Decode("Invalid latitude".to_string()))?;
     let long = locinfo
         .longitude
         .parse::<f64>()
-        .map_err(|_| Error::Client("Invalid longitude".to_string()))?;
+        .map_err(|_| Error::Decode("Invalid longitude".to_string()))?;
 
     let url = format!(
         "https://api.sunrisesunset.io/json?lat={}&lng={}&formatted=0&timezone=Asia/Kolkata",
@@ -88,35 +84,37 @@ pub async fn get_sun_hours() -> Result<SunHours, Error> {
     );
 
     // Make the HTTP request
-    let response = reqwest::get(&url)
+    let response = client
+        .get(&url)
+        .send()
         .await
-        .map_err(|e| Error::Client(e.to_string()))?
+        .map_err(map_send_error)?
         .json::<SunApiResponse>()
         .await
-        .map_err(|e| Error::Client(e.to_string()))?;
+        .map_err(|e| Error::Decode(e.to_string()))?;
 
     // Check API response status
     if response.status != "OK" {
-        return Err(Error::Client("Failed to retrieve sun hours".to_string()));
+        return Err(Error::Decode("Failed to retrieve sun hours".to_string()));
     }
 
     let today = Local::now().date_naive();
     let date = NaiveDate::from_ymd_opt(today.year(), today.month(), today.day())
-        .ok_or(Error::Client("Invalid date".to_string()))?;
+        .ok_or(Error::Decode("Invalid date".to_string()))?;
 
     let final_sunrise_str = format!("{} {}", date, response.results.sunrise);
     let final_sunset_str = format!("{} {}", date, response.results.sunset);
 
     let sunrise_local = NaiveDateTime::parse_from_str(&final_sunrise_str, "%Y-%m-%d %I:%M:%S %p")
-        .map_err(|e| Error::Client(e.to_string()))?
+        .map_err(|e| Error::Decode(e.to_string()))?
         .and_local_timezone(Local)
         .single()
-        .ok_or(Error::Client("Failed to parse sunrise time".to_string()))?;
+        .ok_or(Error::Decode("Failed to parse sunrise time".to_string()))?;
     let sunset_local = NaiveDateTime::parse_from_str(&final_sunset_str, "%Y-%m-%d %I:%M:%S %p")
-        .map_err(|e| Error::Client(e.to_string()))?
+        .map_err(|e| Error::Decode(e.to_string()))?
         .and_local_timezone(Local)
         .single()
-        .ok_or(Error::Client("Failed to parse sunset time".to_string()))?;
+        .ok_or(Error::Decode("Failed to parse sunset time".to_string()))?;
 
     Ok(SunHours {
         sunrise: sunrise_local,
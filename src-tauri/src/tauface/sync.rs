@@ -0,0 +1,257 @@
+use std::path::Path;
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use tauri::State;
+use tokio::sync::Mutex;
+
+use crate::{
+    datatypes::{BlockType, TimeBlock},
+    error::Error,
+    handshake::{require_feature, FEATURE_SYNC},
+    netutils::ApiClient,
+    persist, schema,
+    tauface::profiles::active_data_dir,
+};
+
+const MIRROR_FILE: &str = "mirror.json";
+
+// Only one sync may move the cursor at a time.
+static SYNC: Mutex<()> = Mutex::const_new(());
+
+// Local copy of the full history, together with the cursor it is current to.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Mirror {
+    pub cursor: Option<String>,
+    pub blocktypes: Vec<BlockType>,
+    // Sorted by start time, which identifies a block.
+    pub blocks: Vec<TimeBlock>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SyncDelta {
+    cursor: String,
+    // In the order the server made them, a block can be created and removed
+    // again within one delta.
+    #[serde(default)]
+    changes: Vec<SyncChange>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+enum SyncChange {
+    Block(TimeBlock),
+    Blocktype(BlockType),
+    // Start time of a block that was removed, e.g. by a split or adjust.
+    Deleted(DateTime<Local>),
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SyncSummary {
+    pub cursor: String,
+    pub changed: usize,
+    pub blocks: usize,
+    pub blocktypes: usize,
+}
+
+#[tauri::command]
+pub async fn sync_history(
+    app_handle: tauri::AppHandle,
+    client: State<'_, ApiClient>,
+) -> Result<SyncSummary, Error> {
+    let data_dir = active_data_dir(&app_handle)?;
+    sync_internal(&client, &data_dir).await
+}
+
+pub async fn sync_internal(client: &ApiClient, data_dir: &Path) -> Result<SyncSummary, Error> {
+    require_feature(data_dir, FEATURE_SYNC).await?;
+    let _sync = SYNC.lock().await;
+    let mut mirror = read_mirror(data_dir)?;
+    let delta: SyncDelta = match &mirror.cursor {
+        Some(cursor) => {
+            client
+                .make_get_request("/sync", data_dir, Some(&[("since", cursor)]))
+                .await?
+        }
+        None => client.make_get_request("/sync", data_dir, None).await?,
+    };
+    let changed = delta.changes.len();
+    mirror.merge(delta);
+    write_mirror(data_dir, &mirror)?;
+    log::info!(
+        "Synced {} changes, mirror holds {} blocks",
+        changed,
+        mirror.blocks.len()
+    );
+    Ok(SyncSummary {
+        cursor: mirror.cursor.unwrap_or_default(),
+        changed,
+        blocks: mirror.blocks.len(),
+        blocktypes: mirror.blocktypes.len(),
+    })
+}
+
+impl Mirror {
+    fn merge(&mut self, delta: SyncDelta) {
+        for change in delta.changes {
+            match change {
+                SyncChange::Block(block) => match self
+                    .blocks
+                    .binary_search_by_key(&block.start_time, |known| known.start_time)
+                {
+                    Ok(idx) => self.blocks[idx] = block,
+                    Err(idx) => self.blocks.insert(idx, block),
+                },
+                SyncChange::Blocktype(blocktype) => match self
+                    .blocktypes
+                    .iter_mut()
+                    .find(|known| known.id == blocktype.id)
+                {
+                    Some(known) => *known = blocktype,
+                    None => self.blocktypes.push(blocktype),
+                },
+                SyncChange::Deleted(start_time) => {
+                    self.blocks.retain(|block| block.start_time != start_time);
+                }
+            }
+        }
+        self.blocktypes.sort_by_key(|blocktype| blocktype.id);
+        self.cursor = Some(delta.cursor);
+    }
+}
+
+pub fn read_mirror(data_dir: &Path) -> Result<Mirror, Error> {
+    let mirror_path = data_dir.join(MIRROR_FILE);
+    if !mirror_path.exists() {
+        return Ok(Mirror::default());
+    }
+    persist::read_versioned(&mirror_path, &schema::MIRROR)
+}
+
+// The cursor is stored in the same file as the data it describes and the file
+// is replaced atomically, so an interrupted sync leaves the previous mirror
+// and cursor intact instead of skipping changes.
+fn write_mirror(data_dir: &Path, mirror: &Mirror) -> Result<(), Error> {
+    persist::write_versioned(&data_dir.join(MIRROR_FILE), &schema::MIRROR, mirror)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::fake_server::{Fixture, Scripted};
+
+    fn block(start: &str, end: &str, title: &str) -> serde_json::Value {
+        json!({
+            "start_time": start,
+            "end_time": end,
+            "block_type_id": 1,
+            "title": title,
+        })
+    }
+
+    #[tokio::test]
+    async fn sync_downloads_history_once_then_only_changes() {
+        let fixture = Fixture::logged_in().await;
+        let server = &fixture.server;
+        server.push_sync_change(json!({
+            "blocktype": { "id": 1, "name": "Work", "color": { "r": 1, "g": 2, "b": 3 } },
+        }));
+        server.push_sync_change(json!({
+            "block": block("2024-05-01T10:00:00+00:00", "2024-05-01T12:00:00+00:00", "Write"),
+        }));
+        server.push_sync_change(json!({
+            "block": block("2024-05-01T09:00:00+00:00", "2024-05-01T10:00:00+00:00", "Read"),
+        }));
+
+        let summary = sync_internal(&fixture.client, fixture.data_dir())
+            .await
+            .unwrap();
+        assert_eq!(
+            summary,
+            SyncSummary {
+                cursor: "3".to_string(),
+                changed: 3,
+                blocks: 2,
+                blocktypes: 1,
+            }
+        );
+        assert!(server.requests_to("/sync")[0].query.is_empty());
+
+        // Splitting the first block replaces it with two new ones.
+        server.push_sync_change(json!({ "deleted": "2024-05-01T10:00:00+00:00" }));
+        server.push_sync_change(json!({
+            "block": block("2024-05-01T10:00:00+00:00", "2024-05-01T11:00:00+00:00", "Write"),
+        }));
+        server.push_sync_change(json!({
+            "block": block("2024-05-01T11:00:00+00:00", "2024-05-01T12:00:00+00:00", "Edit"),
+        }));
+        let summary = sync_internal(&fixture.client, fixture.data_dir())
+            .await
+            .unwrap();
+        assert_eq!(summary.changed, 3);
+        assert_eq!(server.requests_to("/sync")[1].query, "since=3");
+
+        let mirror = read_mirror(fixture.data_dir()).unwrap();
+        let titles: Vec<_> = mirror.blocks.iter().map(|block| &block.title[..]).collect();
+        assert_eq!(titles, ["Read", "Write", "Edit"]);
+        assert_eq!(mirror.cursor.as_deref(), Some("6"));
+    }
+
+    #[tokio::test]
+    async fn changes_within_one_sync_apply_in_order() {
+        let fixture = Fixture::logged_in().await;
+        let server = &fixture.server;
+        // A split creates an 11:00 block which an adjust then moves to 11:10.
+        server.push_sync_change(json!({
+            "block": block("2024-05-01T11:00:00+00:00", "2024-05-01T12:00:00+00:00", "Edit"),
+        }));
+        server.push_sync_change(json!({ "deleted": "2024-05-01T11:00:00+00:00" }));
+        server.push_sync_change(json!({
+            "block": block("2024-05-01T11:10:00+00:00", "2024-05-01T12:00:00+00:00", "Edit"),
+        }));
+        // And a block that is created and deleted again.
+        server.push_sync_change(json!({
+            "block": block("2024-05-01T13:00:00+00:00", "2024-05-01T14:00:00+00:00", "Gone"),
+        }));
+        server.push_sync_change(json!({ "deleted": "2024-05-01T13:00:00+00:00" }));
+        sync_internal(&fixture.client, fixture.data_dir())
+            .await
+            .unwrap();
+
+        let mirror = read_mirror(fixture.data_dir()).unwrap();
+        assert_eq!(mirror.blocks.len(), 1);
+        assert_eq!(
+            mirror.blocks[0].start_time,
+            DateTime::parse_from_rfc3339("2024-05-01T11:10:00+00:00").unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_sync_keeps_previous_cursor() {
+        let fixture = Fixture::logged_in().await;
+        fixture.server.push_sync_change(json!({
+            "block": block("2024-05-01T09:00:00+00:00", "2024-05-01T10:00:00+00:00", "Read"),
+        }));
+        sync_internal(&fixture.client, fixture.data_dir())
+            .await
+            .unwrap();
+
+        fixture.server.script(
+            "/sync",
+            Scripted::Respond {
+                status: 200,
+                body: json!({ "cursor": "2", "changes": "not a list" }),
+            },
+        );
+        assert!(matches!(
+            sync_internal(&fixture.client, fixture.data_dir()).await,
+            Err(Error::Decode(_))
+        ));
+        let mirror = read_mirror(fixture.data_dir()).unwrap();
+        assert_eq!(mirror.cursor.as_deref(), Some("1"));
+        assert_eq!(mirror.blocks.len(), 1);
+    }
+}
@@ -0,0 +1,160 @@
+use std::{path::Path, sync::Arc};
+
+use reqwest::ClientBuilder;
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    crypto::ring,
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+
+use crate::error::Error;
+
+pub const SERVER_CA_FILE: &str = "server_ca.pem";
+
+pub struct TlsSettings {
+    pub ca_pem: Option<Vec<u8>>,
+    pub fingerprint: Option<String>,
+}
+
+impl TlsSettings {
+    pub fn load(data_dir: &Path, fingerprint: Option<String>) -> Result<Self, Error> {
+        let ca_path = data_dir.join(SERVER_CA_FILE);
+        let ca_pem = if ca_path.exists() {
+            Some(std::fs::read(&ca_path).map_err(|e| Error::Io(e.to_string()))?)
+        } else {
+            None
+        };
+        Ok(Self {
+            ca_pem,
+            fingerprint,
+        })
+    }
+}
+
+pub fn apply_tls(builder: ClientBuilder, settings: &TlsSettings) -> Result<ClientBuilder, Error> {
+    if settings.ca_pem.is_none() && settings.fingerprint.is_none() {
+        return Ok(builder);
+    }
+
+    let provider = Arc::new(ring::default_provider());
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    if let Some(ca_pem) = &settings.ca_pem {
+        for cert in parse_certificates(ca_pem)? {
+            roots
+                .add(cert)
+                .map_err(|e| Error::InvalidInput(format!("Invalid CA certificate: {}", e)))?;
+        }
+    }
+    let webpki = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+        .build()
+        .map_err(|e| Error::Tls(e.to_string()))?;
+    let fingerprint = settings
+        .fingerprint
+        .as_deref()
+        .map(normalize_fingerprint)
+        .transpose()?;
+    let verifier = PinnedVerifier {
+        webpki,
+        fingerprint,
+    };
+
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| Error::Tls(e.to_string()))?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+
+    Ok(builder.use_preconfigured_tls(config))
+}
+
+pub fn parse_certificates(pem: &[u8]) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let certs = rustls_pemfile::certs(&mut &pem[..])
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| Error::InvalidInput(format!("Invalid certificate file: {}", e)))?;
+    if certs.is_empty() {
+        return Err(Error::InvalidInput(
+            "No certificates found in certificate file".to_string(),
+        ));
+    }
+    Ok(certs)
+}
+
+fn normalize_fingerprint(fingerprint: &str) -> Result<String, Error> {
+    let normalized = fingerprint
+        .chars()
+        .filter(|c| *c != ':' && !c.is_whitespace())
+        .collect::<String>()
+        .to_lowercase();
+    if normalized.len() != 64 || !normalized.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(Error::InvalidInput(
+            "Certificate fingerprint must be a SHA-256 hex digest".to_string(),
+        ));
+    }
+    Ok(normalized)
+}
+
+// A pinned fingerprint is trusted on its own so self-signed servers work
+// without a CA, but any other certificate is then rejected outright.
+#[derive(Debug)]
+struct PinnedVerifier {
+    webpki: Arc<WebPkiServerVerifier>,
+    fingerprint: Option<String>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match &self.fingerprint {
+            Some(fingerprint) => {
+                if sha256::digest(end_entity.as_ref()) == *fingerprint {
+                    Ok(ServerCertVerified::assertion())
+                } else {
+                    Err(rustls::Error::General(
+                        "Server certificate does not match the pinned fingerprint".to_string(),
+                    ))
+                }
+            }
+            None => self.webpki.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                ocsp_response,
+                now,
+            ),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.webpki.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.webpki.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.webpki.supported_verify_schemes()
+    }
+}
@@ -3,6 +3,7 @@ import Home from "../views/Home.vue";
 import History from "../views/History.vue";
 import Analysis from "../views/Analysis.vue";
 import HistoryTimeCards from "../views/HistoryTimeCards.vue";
+import Diagnostics from "../views/Diagnostics.vue";
 
 const routes = [
   { path: "/", name: "Home", component: Home },
@@ -14,6 +15,7 @@ const routes = [
     props: true,
   },
   { path: "/analysis", name: "Analysis", component: Analysis },
+  { path: "/diagnostics", name: "Diagnostics", component: Diagnostics },
 ];
 
 const router: Router = createRouter({